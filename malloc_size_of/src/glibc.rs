@@ -0,0 +1,52 @@
+// Copyright 2016-2017 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Heap size measurement for glibc's malloc, which is what Rust's `System`
+//! allocator uses on Linux.
+//!
+//! glibc cannot find an allocation from an interior pointer, so there is no
+//! `enclosing_size_of_op`, and types like `HashMap` and `VecDeque` are
+//! measured with estimates.
+
+use crate::{MallocSizeOfOps, MallocSizeOfOpsBuilder};
+use core::ffi::c_void;
+
+extern "C" {
+    fn malloc_usable_size(ptr: *mut c_void) -> usize;
+}
+
+/// Get the size of the allocation at `ptr`, which must have been allocated by
+/// glibc's malloc.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn usable_size(ptr: *const c_void) -> usize {
+    malloc_usable_size(ptr as *mut c_void)
+}
+
+/// A builder for `MallocSizeOfOps` that uses [`usable_size`].
+pub fn builder() -> MallocSizeOfOpsBuilder {
+    MallocSizeOfOps::builder().size_of_op(usable_size)
+}
+
+#[test]
+fn test_usable_size() {
+    use crate::{MallocShallowSizeOf, MallocSizeOf};
+    use std::collections::HashMap;
+
+    let mut ops = builder().build();
+    assert!(!ops.has_malloc_enclosing_size_of());
+
+    let v: Vec<u8> = Vec::with_capacity(100);
+    let n = v.size_of(&mut ops);
+    assert!((100..100 + 32).contains(&n), "{}", n);
+    assert_eq!(unsafe { ops.malloc_size_of(Vec::<u64>::new().as_ptr()) }, 0);
+
+    let m: HashMap<u64, u64> = (0..10).map(|i| (i, i)).collect();
+    assert!(m.shallow_size_of(&mut ops) >= m.capacity() * 16);
+}
@@ -8,12 +8,15 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use crate::{MallocConditionalShallowSizeOf, MallocConditionalSizeOf};
 use crate::{MallocShallowSizeOf, MallocSizeOf, MallocSizeOfOps};
+use crate::{MallocUnconditionalShallowSizeOf, MallocUnconditionalSizeOf};
 use core::cell::{Cell, RefCell};
+use core::ffi::c_void;
 
-use core::hash::Hash;
+use core::alloc::Layout;
 use core::marker::PhantomData;
-use core::mem::size_of;
+use core::mem::{size_of, MaybeUninit};
 use core::num::{NonZeroI128, NonZeroI16, NonZeroI32, NonZeroI64, NonZeroI8, NonZeroIsize};
 use core::num::{NonZeroU128, NonZeroU16, NonZeroU32, NonZeroU64, NonZeroU8, NonZeroUsize};
 use core::ops::{Range, RangeFrom, RangeInclusive, RangeTo};
@@ -24,13 +27,15 @@ use core::sync::atomic::{AtomicU16, AtomicU32, AtomicU64, AtomicU8, AtomicUsize}
 use alloc::borrow::{Cow, ToOwned};
 use alloc::boxed::Box;
 use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use alloc::rc::{Rc, Weak as RcWeak};
 use alloc::string::String;
+use alloc::sync::{Arc, Weak as ArcWeak};
 use alloc::vec::Vec;
 
 #[cfg(feature = "std")]
 use std::{
     collections::{HashMap, HashSet},
-    hash::BuildHasher,
+    hash::{BuildHasher, Hash},
     sync::Mutex,
 };
 
@@ -42,9 +47,15 @@ impl MallocSizeOf for void::Void {
     fn size_of(&self, _ops: &mut MallocSizeOfOps) -> usize {
         void::unreachable(*self)
     }
+
+    #[inline(always)]
+    fn never_owns_heap() -> bool {
+        true
+    }
 }
 
-/// For use on types where size_of() returns 0.
+/// For use on sized types where size_of() returns 0. These types also never
+/// own heap memory, so containers of them don't measure each element.
 #[macro_export]
 macro_rules! malloc_size_of_is_0(
     ($($ty:ty),+) => (
@@ -54,6 +65,11 @@ macro_rules! malloc_size_of_is_0(
                 fn size_of(&self, _: &mut $crate::MallocSizeOfOps) -> usize {
                     0
                 }
+
+                #[inline(always)]
+                fn never_owns_heap() -> bool {
+                    true
+                }
             }
         )+
     );
@@ -64,16 +80,30 @@ macro_rules! malloc_size_of_is_0(
             fn size_of(&self, _: &mut $crate::MallocSizeOfOps) -> usize {
                 0
             }
+
+            #[inline(always)]
+            fn never_owns_heap() -> bool {
+                true
+            }
         }
         )+
     );
 );
 
-malloc_size_of_is_0!((), bool, char, str);
+malloc_size_of_is_0!((), bool, char);
 malloc_size_of_is_0!(u8, u16, u32, u64, u128, usize);
 malloc_size_of_is_0!(i8, i16, i32, i64, i128, isize);
 malloc_size_of_is_0!(f32, f64);
 
+// Unsized, so it can't say that it never owns heap memory, but it never
+// appears by value in containers anyway.
+impl MallocSizeOf for str {
+    #[inline(always)]
+    fn size_of(&self, _: &mut MallocSizeOfOps) -> usize {
+        0
+    }
+}
+
 malloc_size_of_is_0!(AtomicBool);
 malloc_size_of_is_0!(AtomicU8, AtomicU16, AtomicU32, AtomicU64, AtomicUsize);
 malloc_size_of_is_0!(AtomicI8, AtomicI16, AtomicI32, AtomicI64, AtomicIsize);
@@ -94,11 +124,74 @@ malloc_size_of_is_0!(
     NonZeroI128
 );
 
+/// Measures the `len` items of `iter` with `f`, or only a sample of them if
+/// `ops` is configured to sample collections this large. Sampled totals are
+/// extrapolated, and replace the measurements of the sampled items in the
+/// counts of measured and estimated bytes. Stops early if the budget of `ops`
+/// runs out.
+pub(crate) fn size_of_elems<I, F>(iter: I, len: usize, ops: &mut MallocSizeOfOps, mut f: F) -> usize
+where
+    I: Iterator,
+    F: FnMut(I::Item, &mut MallocSizeOfOps) -> usize,
+{
+    let (stride, start) = match ops.sampling {
+        Some(ref mut sampling) if len > sampling.threshold => {
+            let stride = len / sampling.sample_size;
+            (stride, (sampling.next_u64() % stride as u64) as usize)
+        }
+        _ => {
+            let mut n = 0;
+            for item in iter {
+                if ops.budget_exhausted() {
+                    break;
+                }
+                n += f(item, ops);
+            }
+            return n;
+        }
+    };
+
+    let measured = ops.measured_bytes.get();
+    let estimated = ops.estimated_bytes.get();
+    let mut count = 0.0;
+    let mut sum = 0.0;
+    let mut sum_of_squares = 0.0;
+    for item in iter.skip(start).step_by(stride) {
+        if ops.budget_exhausted() {
+            break;
+        }
+        let n = f(item, ops) as f64;
+        count += 1.0;
+        sum += n;
+        sum_of_squares += n * n;
+    }
+    ops.measured_bytes.set(measured);
+    ops.estimated_bytes.set(estimated);
+
+    if count == 0.0 {
+        return 0;
+    }
+    let len = len as f64;
+    let mean = sum / count;
+    if count > 1.0 {
+        // The variance of the extrapolated total, with the finite population
+        // correction.
+        let sample_variance = ((sum_of_squares - count * mean * mean) / (count - 1.0)).max(0.0);
+        ops.sampling_variance += len * len * sample_variance / count * (1.0 - count / len);
+    }
+    ops.record_estimate((mean * len + 0.5) as usize)
+}
+
 impl<T: ?Sized> MallocSizeOf for &'_ T {
     fn size_of(&self, _ops: &mut MallocSizeOfOps) -> usize {
         // Zero makes sense for a non-owning reference.
         0
     }
+
+    #[inline(always)]
+    fn never_owns_heap() -> bool {
+        true
+    }
 }
 
 impl<T: ?Sized> MallocSizeOf for &'_ mut T {
@@ -106,6 +199,11 @@ impl<T: ?Sized> MallocSizeOf for &'_ mut T {
         // Zero makes sense for a non-owning reference.
         0
     }
+
+    #[inline(always)]
+    fn never_owns_heap() -> bool {
+        true
+    }
 }
 
 // PhantomData is always 0.
@@ -113,11 +211,21 @@ impl<T> MallocSizeOf for PhantomData<T> {
     fn size_of(&self, _ops: &mut MallocSizeOfOps) -> usize {
         0
     }
+
+    #[inline(always)]
+    fn never_owns_heap() -> bool {
+        true
+    }
 }
 
 impl<T: MallocSizeOf, const N: usize> MallocSizeOf for [T; N] {
     fn size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
-        self.iter().fold(0, |acc, item| acc + item.size_of(ops))
+        self[..].size_of(ops)
+    }
+
+    #[inline(always)]
+    fn never_owns_heap() -> bool {
+        T::never_owns_heap()
     }
 }
 
@@ -129,6 +237,11 @@ where
     fn size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
         self.0.size_of(ops) + self.1.size_of(ops)
     }
+
+    #[inline(always)]
+    fn never_owns_heap() -> bool {
+        T1::never_owns_heap() && T2::never_owns_heap()
+    }
 }
 
 impl<T1, T2, T3> MallocSizeOf for (T1, T2, T3)
@@ -140,6 +253,11 @@ where
     fn size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
         self.0.size_of(ops) + self.1.size_of(ops) + self.2.size_of(ops)
     }
+
+    #[inline(always)]
+    fn never_owns_heap() -> bool {
+        T1::never_owns_heap() && T2::never_owns_heap() && T3::never_owns_heap()
+    }
 }
 
 impl<T1, T2, T3, T4> MallocSizeOf for (T1, T2, T3, T4)
@@ -152,15 +270,22 @@ where
     fn size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
         self.0.size_of(ops) + self.1.size_of(ops) + self.2.size_of(ops) + self.3.size_of(ops)
     }
+
+    #[inline(always)]
+    fn never_owns_heap() -> bool {
+        T1::never_owns_heap()
+            && T2::never_owns_heap()
+            && T3::never_owns_heap()
+            && T4::never_owns_heap()
+    }
 }
 
 impl<T: MallocSizeOf> MallocSizeOf for [T] {
     fn size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
-        let mut n = 0;
-        for elem in self.iter() {
-            n += elem.size_of(ops);
+        if T::never_owns_heap() {
+            return 0;
         }
-        n
+        T::size_of_slice(self, ops)
     }
 }
 
@@ -168,21 +293,41 @@ impl<T: MallocSizeOf> MallocSizeOf for Range<T> {
     fn size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
         self.start.size_of(ops) + self.end.size_of(ops)
     }
+
+    #[inline(always)]
+    fn never_owns_heap() -> bool {
+        T::never_owns_heap()
+    }
 }
 impl<T: MallocSizeOf> MallocSizeOf for RangeInclusive<T> {
     fn size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
         self.start().size_of(ops) + self.end().size_of(ops)
     }
+
+    #[inline(always)]
+    fn never_owns_heap() -> bool {
+        T::never_owns_heap()
+    }
 }
 impl<T: MallocSizeOf> MallocSizeOf for RangeTo<T> {
     fn size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
         self.end.size_of(ops)
     }
+
+    #[inline(always)]
+    fn never_owns_heap() -> bool {
+        T::never_owns_heap()
+    }
 }
 impl<T: MallocSizeOf> MallocSizeOf for RangeFrom<T> {
     fn size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
         self.start.size_of(ops)
     }
+
+    #[inline(always)]
+    fn never_owns_heap() -> bool {
+        T::never_owns_heap()
+    }
 }
 
 impl<T: MallocSizeOf> MallocSizeOf for Option<T> {
@@ -192,6 +337,11 @@ impl<T: MallocSizeOf> MallocSizeOf for Option<T> {
             None => 0,
         }
     }
+
+    #[inline(always)]
+    fn never_owns_heap() -> bool {
+        T::never_owns_heap()
+    }
 }
 
 impl<T: MallocSizeOf, E: MallocSizeOf> MallocSizeOf for Result<T, E> {
@@ -201,18 +351,33 @@ impl<T: MallocSizeOf, E: MallocSizeOf> MallocSizeOf for Result<T, E> {
             Err(ref e) => e.size_of(ops),
         }
     }
+
+    #[inline(always)]
+    fn never_owns_heap() -> bool {
+        T::never_owns_heap() && E::never_owns_heap()
+    }
 }
 
 impl<T: MallocSizeOf + Copy> MallocSizeOf for Cell<T> {
     fn size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
         self.get().size_of(ops)
     }
+
+    #[inline(always)]
+    fn never_owns_heap() -> bool {
+        T::never_owns_heap()
+    }
 }
 
 impl<T: MallocSizeOf> MallocSizeOf for RefCell<T> {
     fn size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
         self.borrow().size_of(ops)
     }
+
+    #[inline(always)]
+    fn never_owns_heap() -> bool {
+        T::never_owns_heap()
+    }
 }
 
 impl<B: ?Sized + ToOwned> MallocSizeOf for Cow<'_, B>
@@ -227,35 +392,90 @@ where
     }
 }
 
+/// Should `len` values be measured with the batched size op, rather than one
+/// by one? Sampled collections measure few values, so they aren't batched.
+fn use_batch_size_of(ops: &MallocSizeOfOps, len: usize) -> bool {
+    ops.has_malloc_batch_size_of() && ops.sampling.as_ref().map_or(true, |s| len <= s.threshold)
+}
+
+/// Measures the heap allocations holding a `U` owned by each of `items` with
+/// the batched size op. `ptr` returns a pointer to an item's allocation, or
+/// `None` if it hasn't allocated.
+fn batch_size_of<T, U: ?Sized>(
+    items: &[T],
+    ops: &MallocSizeOfOps,
+    ptr: impl Fn(&T) -> Option<*const c_void>,
+) -> usize {
+    let ptrs: Vec<_> = items.iter().filter_map(ptr).collect();
+    unsafe { ops.malloc_batch_size_of::<U>(&ptrs) }
+}
+
 impl MallocSizeOf for String {
     fn size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
-        unsafe { ops.malloc_size_of(self.as_ptr()) }
+        unsafe { ops.malloc_buffer_size_of(self.as_ptr(), self.capacity()) }
+    }
+
+    fn size_of_slice(items: &[Self], ops: &mut MallocSizeOfOps) -> usize {
+        if !use_batch_size_of(ops, items.len()) {
+            return size_of_elems(items.iter(), items.len(), ops, |s, ops| s.size_of(ops));
+        }
+        batch_size_of::<_, str>(items, ops, |s| {
+            if s.capacity() == 0 {
+                None
+            } else {
+                Some(s.as_ptr() as *const c_void)
+            }
+        })
     }
 }
 
 impl<T: ?Sized> MallocShallowSizeOf for Box<T> {
     fn shallow_size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
-        unsafe { ops.malloc_size_of(&**self) }
+        unsafe { ops.malloc_layout_size_of(&**self, Layout::for_value(&**self)) }
     }
 }
 
 impl<T: MallocSizeOf + ?Sized> MallocSizeOf for Box<T> {
     fn size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
+        // Don't walk any further down long chains of boxes.
+        if ops.budget_exhausted() {
+            return 0;
+        }
         self.shallow_size_of(ops) + (**self).size_of(ops)
     }
+
+    fn size_of_slice(items: &[Self], ops: &mut MallocSizeOfOps) -> usize {
+        if !use_batch_size_of(ops, items.len()) {
+            return size_of_elems(items.iter(), items.len(), ops, |b, ops| b.size_of(ops));
+        }
+        let mut n = batch_size_of::<_, T>(items, ops, |b| {
+            if core::mem::size_of_val(&**b) == 0 {
+                None
+            } else {
+                Some(&**b as *const T as *const c_void)
+            }
+        });
+        for b in items {
+            if ops.budget_exhausted() {
+                break;
+            }
+            n += (**b).size_of(ops);
+        }
+        n
+    }
 }
 
 impl<T> MallocShallowSizeOf for Vec<T> {
     fn shallow_size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
-        unsafe { ops.malloc_size_of(self.as_ptr()) }
+        unsafe { ops.malloc_buffer_size_of(self.as_ptr(), self.capacity()) }
     }
 }
 
 impl<T: MallocSizeOf> MallocSizeOf for Vec<T> {
     fn size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
         let mut n = self.shallow_size_of(ops);
-        for elem in self.iter() {
-            n += elem.size_of(ops);
+        if !T::never_owns_heap() {
+            n += T::size_of_slice(self, ops);
         }
         n
     }
@@ -264,16 +484,16 @@ impl<T: MallocSizeOf> MallocSizeOf for Vec<T> {
 impl<T> MallocShallowSizeOf for VecDeque<T> {
     fn shallow_size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
         if ops.has_malloc_enclosing_size_of() {
-            if let Some(front) = self.front() {
-                // The front element is an interior pointer.
-                unsafe { ops.malloc_enclosing_size_of(front) }
-            } else {
-                // This assumes that no memory is allocated when the VecDeque is empty.
+            if self.capacity() == 0 || size_of::<T>() == 0 {
+                // Nothing is allocated.
                 0
+            } else {
+                // The front slice points into the buffer at the head, even
+                // when the VecDeque is empty.
+                unsafe { ops.malloc_enclosing_size_of(self.as_slices().0.as_ptr()) }
             }
         } else {
-            // An estimate.
-            self.capacity() * size_of::<T>()
+            ops.record_estimate(self.capacity() * size_of::<T>())
         }
     }
 }
@@ -281,71 +501,213 @@ impl<T> MallocShallowSizeOf for VecDeque<T> {
 impl<T: MallocSizeOf> MallocSizeOf for VecDeque<T> {
     fn size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
         let mut n = self.shallow_size_of(ops);
-        for elem in self.iter() {
-            n += elem.size_of(ops);
+        if !T::never_owns_heap() {
+            let (front, back) = self.as_slices();
+            n += T::size_of_slice(front, ops) + T::size_of_slice(back, ops);
         }
         n
     }
 }
 
-impl<K, V> MallocShallowSizeOf for BTreeMap<K, V>
-where
-    K: Eq + Hash,
-{
+// Mirrors of the node types in `alloc::collections::btree::node`, used to
+// estimate node sizes when the allocator can't be asked.
+const BTREE_CAPACITY: usize = 11;
+
+#[allow(dead_code)]
+struct BTreeLeafNode<K, V> {
+    parent: Option<core::ptr::NonNull<()>>,
+    parent_idx: MaybeUninit<u16>,
+    len: u16,
+    keys: [MaybeUninit<K>; BTREE_CAPACITY],
+    vals: [MaybeUninit<V>; BTREE_CAPACITY],
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+struct BTreeInternalNode<K, V> {
+    data: BTreeLeafNode<K, V>,
+    edges: [MaybeUninit<core::ptr::NonNull<()>>; BTREE_CAPACITY + 1],
+}
+
+/// Measures the nodes of a B-tree, given an in-order iterator over references
+/// to one of the arrays stored in the nodes (the keys or the values). `T` must
+/// not be zero-sized, so that every element has a distinct address.
+///
+/// Elements of the same node are adjacent in memory, which lets us spot the
+/// first element visited in each node. With an enclosing size op that element
+/// is measured directly. Otherwise, the nodes are counted and their sizes
+/// estimated: in-order iteration alternates between a run of elements from a
+/// leaf and a single element from an internal node, so the number of runs of
+/// adjacent elements gives the number of leaves.
+fn btree_shallow_size_of<'a, K, V, T: 'a>(
+    elems: impl Iterator<Item = &'a T>,
+    ops: &mut MallocSizeOfOps,
+) -> usize {
+    debug_assert!(size_of::<T>() != 0);
+    let measure = ops.has_malloc_enclosing_size_of();
+    // For every node on the path from the root to the current node, the
+    // address its next element would have.
+    let mut open_nodes: Vec<usize> = Vec::new();
+    let mut next_addr = None;
+    let mut nodes = 0;
+    let mut runs = 0;
+    let mut n = 0;
+    for elem in elems {
+        let addr = elem as *const T as usize;
+        if next_addr != Some(addr) {
+            runs += 1;
+        }
+        next_addr = Some(addr + size_of::<T>());
+        match open_nodes.iter().rposition(|&a| a == addr) {
+            Some(i) => {
+                open_nodes.truncate(i);
+            }
+            None => {
+                nodes += 1;
+                if measure {
+                    n += unsafe { ops.malloc_enclosing_size_of(elem) };
+                }
+            }
+        }
+        open_nodes.push(addr + size_of::<T>());
+    }
+    if measure {
+        n
+    } else {
+        let leaves = (runs + 1) / 2;
+        let internal_nodes = nodes - leaves;
+        ops.record_estimate(
+            leaves * size_of::<BTreeLeafNode<K, V>>()
+                + internal_nodes * size_of::<BTreeInternalNode<K, V>>(),
+        )
+    }
+}
+
+/// Estimates the nodes of a B-tree whose keys and values are all zero-sized,
+/// assuming full leaves.
+fn btree_zst_shallow_size_of<K, V>(len: usize, ops: &mut MallocSizeOfOps) -> usize {
+    let leaves = (len + BTREE_CAPACITY - 1) / BTREE_CAPACITY;
+    ops.record_estimate(leaves * size_of::<BTreeLeafNode<K, V>>())
+}
+
+impl<K, V> MallocShallowSizeOf for BTreeMap<K, V> {
     fn shallow_size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
-        if ops.has_malloc_enclosing_size_of() {
-            self.values()
-                .next()
-                .map_or(0, |v| unsafe { ops.malloc_enclosing_size_of(v) })
+        if self.is_empty() {
+            // An empty map has no root node.
+            0
+        } else if size_of::<K>() != 0 {
+            btree_shallow_size_of::<K, V, _>(self.keys(), ops)
+        } else if size_of::<V>() != 0 {
+            btree_shallow_size_of::<K, V, _>(self.values(), ops)
         } else {
-            self.len() * (size_of::<V>() + size_of::<K>() + size_of::<usize>())
+            btree_zst_shallow_size_of::<K, V>(self.len(), ops)
         }
     }
 }
 
 impl<K, V> MallocSizeOf for BTreeMap<K, V>
 where
-    K: Eq + Hash + MallocSizeOf,
+    K: MallocSizeOf,
     V: MallocSizeOf,
 {
     fn size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
         let mut n = self.shallow_size_of(ops);
-        for (k, v) in self.iter() {
-            n += k.size_of(ops);
-            n += v.size_of(ops);
+        if !(K::never_owns_heap() && V::never_owns_heap()) {
+            n += size_of_elems(self.iter(), self.len(), ops, |(k, v), ops| {
+                k.size_of(ops) + v.size_of(ops)
+            });
         }
         n
     }
 }
 
-impl<T> MallocShallowSizeOf for BTreeSet<T>
-where
-    T: Ord,
-{
+impl<T> MallocShallowSizeOf for BTreeSet<T> {
     fn shallow_size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
-        if ops.has_malloc_enclosing_size_of() {
-            self.iter()
-                .next()
-                .map_or(0, |v| unsafe { ops.malloc_enclosing_size_of(v) })
+        // A `BTreeSet<T>` is a `BTreeMap<T, SetValZST>`.
+        if self.is_empty() {
+            0
+        } else if size_of::<T>() != 0 {
+            btree_shallow_size_of::<T, (), _>(self.iter(), ops)
         } else {
-            self.len() * (size_of::<T>() + size_of::<usize>())
+            btree_zst_shallow_size_of::<T, ()>(self.len(), ops)
         }
     }
 }
 
 impl<T> MallocSizeOf for BTreeSet<T>
 where
-    T: Ord + MallocSizeOf,
+    T: MallocSizeOf,
 {
     fn size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
         let mut n = self.shallow_size_of(ops);
-        for v in self.iter() {
-            n += v.size_of(ops);
+        if !T::never_owns_heap() {
+            n += size_of_elems(self.iter(), self.len(), ops, |v, ops| v.size_of(ops));
         }
         n
     }
 }
 
+/// The number of control bytes hashbrown processes at once, which depends on
+/// the SIMD implementation it picks for the target.
+#[cfg(all(
+    feature = "std",
+    any(target_arch = "x86", target_arch = "x86_64"),
+    target_feature = "sse2"
+))]
+const HASHBROWN_GROUP_WIDTH: usize = 16;
+#[cfg(all(
+    feature = "std",
+    target_arch = "aarch64",
+    target_feature = "neon",
+    target_endian = "little"
+))]
+const HASHBROWN_GROUP_WIDTH: usize = 8;
+#[cfg(all(
+    feature = "std",
+    not(all(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        target_feature = "sse2"
+    )),
+    not(all(
+        target_arch = "aarch64",
+        target_feature = "neon",
+        target_endian = "little"
+    ))
+))]
+// The generic implementation uses a `u64` group word where that is cheap, which
+// includes wasm32, and a `u32` elsewhere.
+const HASHBROWN_GROUP_WIDTH: usize = if cfg!(any(
+    target_pointer_width = "64",
+    target_arch = "aarch64",
+    target_arch = "x86_64",
+    target_arch = "wasm32"
+)) {
+    8
+} else {
+    4
+};
+
+/// Estimates the size of the allocation behind a std hash table with entries
+/// of type `T`, given its `capacity()`. This reproduces hashbrown's layout: a
+/// power-of-two number of buckets, at most 7/8 of which are usable (all but
+/// one for small tables), followed by one control byte per bucket plus a
+/// trailing group. Tombstones left by removals reduce `capacity()`, so this
+/// may underestimate tables that have seen many removals.
+#[cfg(feature = "std")]
+fn hashbrown_table_size<T>(capacity: usize) -> usize {
+    if capacity == 0 {
+        return 0;
+    }
+    let buckets = if capacity < 8 {
+        (capacity + 1).next_power_of_two().max(4)
+    } else {
+        ((capacity * 8 + 6) / 7).next_power_of_two()
+    };
+    let ctrl_align = core::mem::align_of::<T>().max(HASHBROWN_GROUP_WIDTH);
+    let ctrl_offset = (size_of::<T>() * buckets + ctrl_align - 1) & !(ctrl_align - 1);
+    ctrl_offset + buckets + HASHBROWN_GROUP_WIDTH
+}
+
 #[cfg(feature = "std")]
 impl<T, S> MallocShallowSizeOf for HashSet<T, S>
 where
@@ -353,18 +715,18 @@ where
     S: BuildHasher,
 {
     fn shallow_size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
-        if ops.has_malloc_enclosing_size_of() {
+        if ops.has_malloc_enclosing_size_of() && size_of::<T>() != 0 {
             // The first value from the iterator gives us an interior pointer.
             // `ops.malloc_enclosing_size_of()` then gives us the storage size.
             // This assumes that the `HashSet`'s contents (values and hashes)
             // are all stored in a single contiguous heap allocation.
-            self.iter()
-                .next()
-                .map_or(0, |t| unsafe { ops.malloc_enclosing_size_of(t) })
-        } else {
-            // An estimate.
-            self.capacity() * (size_of::<T>() + size_of::<usize>())
+            if let Some(t) = self.iter().next() {
+                return unsafe { ops.malloc_enclosing_size_of(t) };
+            }
         }
+        // An empty `HashSet` may still hold on to its table, but there is no
+        // pointer into it, so fall back to an estimate from the capacity.
+        ops.record_estimate(hashbrown_table_size::<T>(self.capacity()))
     }
 }
 
@@ -376,8 +738,8 @@ where
 {
     fn size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
         let mut n = self.shallow_size_of(ops);
-        for t in self.iter() {
-            n += t.size_of(ops);
+        if !T::never_owns_heap() {
+            n += size_of_elems(self.iter(), self.len(), ops, |t, ops| t.size_of(ops));
         }
         n
     }
@@ -390,14 +752,21 @@ where
     S: BuildHasher,
 {
     fn shallow_size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
-        // See the implementation for HashSet for details.
+        // See the implementation for HashSet for details. Zero-sized keys or
+        // values don't point into the table, so use whichever isn't.
         if ops.has_malloc_enclosing_size_of() {
-            self.values()
-                .next()
-                .map_or(0, |v| unsafe { ops.malloc_enclosing_size_of(v) })
-        } else {
-            self.capacity() * (size_of::<V>() + size_of::<K>() + size_of::<usize>())
+            let interior = if size_of::<K>() != 0 {
+                self.keys().next().map(|k| k as *const K as *const u8)
+            } else if size_of::<V>() != 0 {
+                self.values().next().map(|v| v as *const V as *const u8)
+            } else {
+                None
+            };
+            if let Some(ptr) = interior {
+                return unsafe { ops.malloc_enclosing_size_of(ptr) };
+            }
         }
+        ops.record_estimate(hashbrown_table_size::<(K, V)>(self.capacity()))
     }
 }
 
@@ -410,9 +779,10 @@ where
 {
     fn size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
         let mut n = self.shallow_size_of(ops);
-        for (k, v) in self.iter() {
-            n += k.size_of(ops);
-            n += v.size_of(ops);
+        if !(K::never_owns_heap() && V::never_owns_heap()) {
+            n += size_of_elems(self.iter(), self.len(), ops, |(k, v), ops| {
+                k.size_of(ops) + v.size_of(ops)
+            });
         }
         n
     }
@@ -429,10 +799,514 @@ impl<T: MallocSizeOf> MallocSizeOf for Mutex<T> {
     fn size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
         (*self.lock().unwrap()).size_of(ops)
     }
+
+    #[inline(always)]
+    fn never_owns_heap() -> bool {
+        T::never_owns_heap()
+    }
+}
+
+/// Get a pointer to the start of the heap allocation backing an `Rc` or `Arc`,
+/// and the layout of that allocation, given a pointer to the value it holds
+/// and the layout of that value. This relies on knowledge of how Rust lays out
+/// `Rc` and `Arc` allocations (a `#[repr(C)]` struct holding two `usize`
+/// reference counts followed by the value), which may change in the future.
+fn rc_allocation<T: ?Sized>(value: *const T, value_layout: Layout) -> (*const u8, Layout) {
+    let (layout, offset) = Layout::new::<[usize; 2]>().extend(value_layout).unwrap();
+    (
+        value.cast::<u8>().wrapping_sub(offset),
+        layout.pad_to_align(),
+    )
 }
 
+macro_rules! malloc_size_of_rc(
+    ($rc:ident) => (
+        impl<T: ?Sized> MallocUnconditionalShallowSizeOf for $rc<T> {
+            fn unconditional_shallow_size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
+                let value_layout = Layout::for_value(&**self);
+                let (heap_ptr, layout) = rc_allocation($rc::as_ptr(self), value_layout);
+                if value_layout.size() == 0 {
+                    // An empty `str` or slice may share a static header
+                    // instead of having an allocation of its own, so the
+                    // allocator mustn't be asked about it.
+                    return ops.record_estimate(layout.size());
+                }
+                unsafe { ops.malloc_layout_size_of(heap_ptr, layout) }
+            }
+        }
+
+        impl<T: MallocSizeOf + ?Sized> MallocUnconditionalSizeOf for $rc<T> {
+            fn unconditional_size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
+                if ops.budget_exhausted() {
+                    return 0;
+                }
+                self.unconditional_shallow_size_of(ops) + (**self).size_of(ops)
+            }
+        }
+
+        impl<T: ?Sized> MallocConditionalShallowSizeOf for $rc<T> {
+            fn conditional_shallow_size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
+                let (heap_ptr, _) = rc_allocation($rc::as_ptr(self), Layout::for_value(&**self));
+                if ops.have_seen_ptr(heap_ptr) {
+                    0
+                } else {
+                    self.unconditional_shallow_size_of(ops)
+                }
+            }
+        }
+
+        impl<T: MallocSizeOf + ?Sized> MallocConditionalSizeOf for $rc<T> {
+            fn conditional_size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
+                let (heap_ptr, _) = rc_allocation($rc::as_ptr(self), Layout::for_value(&**self));
+                if ops.have_seen_ptr(heap_ptr) {
+                    0
+                } else {
+                    self.unconditional_size_of(ops)
+                }
+            }
+        }
+    );
+);
+
+malloc_size_of_rc!(Rc);
+malloc_size_of_rc!(Arc);
+
+// A `Weak` keeps its `Rc`/`Arc` allocation alive. While the value is alive,
+// a `Weak` is measured just like a strong pointer would be, sharing its
+// `have_seen_ptr` entry. Once the value has been dropped, only the two
+// reference counts in the header are still in use, so only they are counted,
+// as an estimate. There are no unconditional impls because a `Weak` is never a
+// "primary" reference.
+macro_rules! malloc_size_of_weak(
+    ($weak:ident) => (
+        impl<T: ?Sized> MallocConditionalShallowSizeOf for $weak<T> {
+            fn conditional_shallow_size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
+                match self.upgrade() {
+                    Some(value) => value.conditional_shallow_size_of(ops),
+                    None => weak_header_size_of(self.as_ptr(), ops),
+                }
+            }
+        }
+
+        impl<T: MallocSizeOf + ?Sized> MallocConditionalSizeOf for $weak<T> {
+            fn conditional_size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
+                match self.upgrade() {
+                    Some(value) => value.conditional_size_of(ops),
+                    None => weak_header_size_of(self.as_ptr(), ops),
+                }
+            }
+        }
+    );
+);
+
+/// Estimate the header of an `Rc` or `Arc` allocation whose value has been
+/// dropped, given the pointer returned by `Weak::as_ptr`. `Weak::new()` doesn't
+/// allocate, and its pointer is `usize::MAX`. This relies on knowledge of how
+/// Rust implements `Weak`, which may change in the future.
+fn weak_header_size_of<T: ?Sized>(value: *const T, ops: &mut MallocSizeOfOps) -> usize {
+    let value = value.cast::<u8>();
+    if value as usize == usize::MAX || ops.have_seen_ptr(value) {
+        0
+    } else {
+        ops.record_estimate(2 * size_of::<usize>())
+    }
+}
+
+malloc_size_of_weak!(RcWeak);
+malloc_size_of_weak!(ArcWeak);
+
 // XXX: we don't want MallocSizeOf to be defined for Rc and Arc. If negative
 // trait bounds are ever allowed, this code should be uncommented.
 // (We do have a compile-fail test for this: rc_arc_must_not_derive_malloc_size_of.rs)
 //impl<T> !MallocSizeOf for Arc<T> { }
 //impl<T> !MallocShallowSizeOf for Arc<T> { }
+
+#[test]
+fn test_rc_and_arc() {
+    let mut ops = MallocSizeOfOps::builder()
+        .size_of_op(fixed_size::<100>)
+        .track_seen_ptrs()
+        .build();
+
+    let a = Arc::new(alloc::vec![1u8; 10]);
+    let b = a.clone();
+    assert_eq!(a.unconditional_size_of(&mut ops), 200);
+    assert_eq!(a.unconditional_shallow_size_of(&mut ops), 100);
+    assert_eq!(a.conditional_size_of(&mut ops), 200);
+    assert_eq!(b.conditional_size_of(&mut ops), 0);
+    assert_eq!(b.conditional_shallow_size_of(&mut ops), 0);
+
+    let r: Rc<str> = Rc::from("abc");
+    assert_eq!(r.conditional_shallow_size_of(&mut ops), 100);
+    assert_eq!(r.clone().conditional_size_of(&mut ops), 0);
+    assert_eq!(ops.seen_ptrs().unwrap().len(), 2);
+}
+
+#[test]
+fn test_weak() {
+    let mut ops = MallocSizeOfOps::builder()
+        .size_of_op(fixed_size::<100>)
+        .track_seen_ptrs()
+        .build();
+
+    assert_eq!(RcWeak::<u8>::new().conditional_size_of(&mut ops), 0);
+    let empty: ArcWeak<dyn core::any::Any> = ArcWeak::<u8>::new();
+    assert_eq!(empty.conditional_shallow_size_of(&mut ops), 0);
+
+    // A live value shares its seen pointer with the strong references.
+    let rc = Rc::new(alloc::vec![1u8; 10]);
+    let weak = Rc::downgrade(&rc);
+    assert_eq!(weak.conditional_size_of(&mut ops), 200);
+    assert_eq!(rc.conditional_size_of(&mut ops), 0);
+    assert_eq!(weak.clone().conditional_size_of(&mut ops), 0);
+
+    let s: Arc<str> = Arc::from("abc");
+    assert_eq!(Arc::downgrade(&s).conditional_size_of(&mut ops), 100);
+    assert_eq!(s.conditional_size_of(&mut ops), 0);
+
+    // Once the value is dropped, only the header is counted, once.
+    drop(rc);
+    let header = 2 * size_of::<usize>();
+    assert_eq!(weak.conditional_size_of(&mut ops), header);
+    assert_eq!(weak.clone().conditional_shallow_size_of(&mut ops), 0);
+    assert_eq!(ops.measured_bytes(), 300);
+    assert_eq!(ops.estimated_bytes(), header);
+}
+
+/// The estimated size of an `Rc` or `Arc` allocation holding a value with
+/// layout `value`: the two counts, then the value, padded to its alignment.
+#[cfg(test)]
+fn rc_estimate(value: Layout) -> usize {
+    let (layout, _) = Layout::new::<[usize; 2]>().extend(value).unwrap();
+    layout.pad_to_align().size()
+}
+
+#[test]
+fn test_estimates() {
+    use alloc::vec;
+
+    let mut ops = MallocSizeOfOps::builder().track_seen_ptrs().build();
+    assert!(!ops.has_malloc_size_of());
+
+    let v: Vec<u64> = Vec::with_capacity(10);
+    assert_eq!(v.size_of(&mut ops), 80);
+
+    let s = String::with_capacity(7);
+    assert_eq!(s.size_of(&mut ops), 7);
+
+    let b: Box<[u16]> = vec![0; 5].into_boxed_slice();
+    assert_eq!(b.size_of(&mut ops), 10);
+
+    let e: Vec<u8> = Vec::new();
+    assert_eq!(e.size_of(&mut ops), 0);
+
+    let rc = Rc::new(0u8);
+    assert_eq!(
+        rc.unconditional_size_of(&mut ops),
+        rc_estimate(Layout::new::<u8>())
+    );
+    let arc: Arc<str> = Arc::from("hello");
+    assert_eq!(
+        arc.conditional_size_of(&mut ops),
+        rc_estimate(Layout::for_value("hello"))
+    );
+    assert_eq!(arc.clone().conditional_size_of(&mut ops), 0);
+
+    assert_eq!(ops.measured_bytes(), 0);
+    assert_eq!(ops.estimated_bytes(), 80 + 7 + 10 + 24 + 24);
+}
+
+#[test]
+fn test_empty_allocations() {
+    // Any call to the size op means a dangling pointer reached the allocator.
+    let mut ops = MallocSizeOfOps::builder()
+        .size_of_op(fixed_size::<{ usize::MAX }>)
+        .build();
+
+    #[repr(align(4096))]
+    struct Page(#[allow(dead_code)] [u8; 4096]);
+    #[repr(align(4096))]
+    struct EmptyPage;
+    malloc_size_of_is_0!(Page, EmptyPage);
+
+    assert_eq!(Vec::<Page>::new().size_of(&mut ops), 0);
+    assert_eq!(Vec::<Page>::new().into_boxed_slice().size_of(&mut ops), 0);
+    assert_eq!(Box::new(EmptyPage).size_of(&mut ops), 0);
+    assert_eq!(Vec::<EmptyPage>::with_capacity(10).size_of(&mut ops), 0);
+    assert_eq!(VecDeque::<Page>::new().size_of(&mut ops), 0);
+    assert_eq!(String::new().size_of(&mut ops), 0);
+    assert_eq!(Box::<str>::from("").size_of(&mut ops), 0);
+    unsafe {
+        assert_eq!(ops.malloc_size_of(Vec::<u64>::new().as_ptr()), 0);
+        assert_eq!(ops.malloc_size_of(Vec::<Page>::new().as_ptr()), 0);
+        assert_eq!(ops.malloc_size_of(Box::<[u8]>::default().as_ref()), 0);
+    }
+    assert_eq!(
+        Arc::<str>::default().unconditional_size_of(&mut ops),
+        rc_estimate(Layout::new::<[u8; 0]>())
+    );
+    assert_eq!(
+        Rc::<[u64]>::default().unconditional_shallow_size_of(&mut ops),
+        rc_estimate(Layout::new::<[u64; 0]>())
+    );
+    assert_eq!(ops.measured_bytes(), 0);
+}
+
+#[test]
+fn test_seen_ptrs() {
+    let mut ops = MallocSizeOfOps::with_seen_ptrs(fixed_size::<100>, None);
+    assert!(ops.seen_ptrs().unwrap().is_empty());
+
+    let a = Arc::new(1u64);
+    let b = Rc::new(2u64);
+    assert_eq!(a.conditional_size_of(&mut ops), 100);
+    assert_eq!(a.clone().conditional_size_of(&mut ops), 0);
+    assert_eq!(b.conditional_size_of(&mut ops), 100);
+    assert_eq!(ops.seen_ptrs().unwrap().len(), 2);
+
+    // Clearing the set starts a new measurement pass.
+    ops.seen_ptrs_mut().unwrap().clear();
+    assert!(ops.seen_ptrs().unwrap().is_empty());
+    assert_eq!(a.conditional_size_of(&mut ops), 100);
+    assert_eq!(ops.seen_ptrs().unwrap().len(), 1);
+
+    // A custom `have_seen_ptr` has no built-in set.
+    let mut ops = MallocSizeOfOps::new(fixed_size::<100>, None, Some(Box::new(|_| false)));
+    assert!(ops.seen_ptrs().is_none());
+    assert!(ops.seen_ptrs_mut().is_none());
+}
+
+#[test]
+fn test_builder() {
+    fn have_seen_ptr() -> Box<crate::VoidPtrToBoolFnMut> {
+        let mut seen = BTreeSet::new();
+        Box::new(move |ptr| !seen.insert(ptr as usize))
+    }
+
+    let mut new_ops = MallocSizeOfOps::new(
+        fixed_size::<100>,
+        Some(fixed_size::<1000>),
+        Some(have_seen_ptr()),
+    );
+    let mut built_ops = MallocSizeOfOps::builder()
+        .size_of_op(fixed_size::<100>)
+        .enclosing_size_of_op(fixed_size::<1000>)
+        .have_seen_ptr_op(have_seen_ptr())
+        .build();
+
+    let boxes = alloc::vec![Box::new(1u8), Box::new(2u8)];
+    let deque: VecDeque<u8> = (0..10).collect();
+    let shared = Arc::new(String::from("shared"));
+    for ops in [&mut new_ops, &mut built_ops] {
+        assert!(ops.has_malloc_size_of());
+        assert!(ops.has_malloc_enclosing_size_of());
+        assert_eq!(boxes.size_of(ops), 300);
+        assert_eq!(deque.size_of(ops), 1000);
+        assert_eq!(shared.conditional_size_of(ops), 200);
+        assert_eq!(shared.clone().conditional_size_of(ops), 0);
+        assert_eq!(ops.measured_bytes(), 1500);
+        assert_eq!(ops.estimated_bytes(), 0);
+    }
+}
+
+#[test]
+fn test_size_of_closures() {
+    let calls = Rc::new(Cell::new(0));
+    let size_of_calls = calls.clone();
+    let mut ops = MallocSizeOfOps::builder()
+        .size_of_closure(Box::new(move |_| {
+            size_of_calls.set(size_of_calls.get() + 1);
+            64
+        }))
+        .enclosing_size_of_closure(Box::new(|_| 128))
+        .build();
+
+    let v = alloc::vec![Box::new(1u8), Box::new(2u8)];
+    assert_eq!(v.size_of(&mut ops), 3 * 64);
+    assert_eq!(calls.get(), 3);
+
+    let d: VecDeque<u8> = VecDeque::with_capacity(4);
+    assert_eq!(d.shallow_size_of(&mut ops), 128);
+    assert_eq!(ops.measured_bytes(), 3 * 64 + 128);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_shared_ops() {
+    use crate::SharedMallocSizeOfOps;
+
+    let shared = Arc::new(SharedMallocSizeOfOps::new(None, None));
+    let value = Arc::new(0u64);
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let shared = shared.clone();
+            let value = value.clone();
+            std::thread::spawn(move || value.conditional_size_of(&mut shared.ops()))
+        })
+        .collect();
+    let total: usize = threads.into_iter().map(|t| t.join().unwrap()).sum();
+    assert_eq!(total, rc_estimate(Layout::new::<u64>()));
+    assert_eq!(shared.seen_ptrs().len(), 1);
+
+    // A thread panicking with the set locked doesn't stop the others.
+    let poisoner = shared.clone();
+    std::thread::spawn(move || {
+        let _seen = poisoner.seen_ptrs();
+        panic!("poisoning the seen pointer set");
+    })
+    .join()
+    .unwrap_err();
+    let other = Arc::new(1u64);
+    assert_eq!(other.conditional_size_of(&mut shared.ops()), total);
+    assert_eq!(shared.seen_ptrs().len(), 2);
+}
+
+/// A size op that reports every allocation as `N` bytes.
+#[cfg(test)]
+pub(crate) extern "C" fn fixed_size<const N: usize>(_: *const c_void) -> usize {
+    N
+}
+
+/// A type that panics if measured, to check that containers skip elements
+/// that never own heap memory.
+#[cfg(test)]
+pub(crate) struct Unmeasurable;
+
+#[cfg(test)]
+impl MallocSizeOf for Unmeasurable {
+    fn size_of(&self, _: &mut MallocSizeOfOps) -> usize {
+        unreachable!("containers should skip elements that never own heap memory")
+    }
+
+    fn never_owns_heap() -> bool {
+        true
+    }
+}
+
+#[test]
+fn test_never_owns_heap() {
+    let mut ops = MallocSizeOfOps::builder().build();
+    let v: Vec<Unmeasurable> = (0..10).map(|_| Unmeasurable).collect();
+    assert_eq!(v.size_of(&mut ops), 0);
+    let v: Vec<(u8, Option<Unmeasurable>)> = (0..10).map(|i| (i, Some(Unmeasurable))).collect();
+    assert_eq!(
+        v.size_of(&mut ops),
+        10 * size_of::<(u8, Option<Unmeasurable>)>()
+    );
+    let a = [[Unmeasurable, Unmeasurable]];
+    assert_eq!(a.size_of(&mut ops), 0);
+
+    assert!(u8::never_owns_heap());
+    assert!(<(u32, [f64; 4], PhantomData<String>)>::never_owns_heap());
+    assert!(!<Option<String>>::never_owns_heap());
+    assert!(!<Vec<u8>>::never_owns_heap());
+}
+
+#[test]
+fn test_sampling() {
+    let strings: Vec<String> = (0..10_000).map(|i| "x".repeat(i * 7919 % 97)).collect();
+    let exact = strings.size_of(&mut MallocSizeOfOps::builder().build());
+
+    let sampled_ops = || {
+        MallocSizeOfOps::builder()
+            .sample_elements(1000, 100, 42)
+            .build()
+    };
+    let mut ops = sampled_ops();
+    let sampled = strings.size_of(&mut ops);
+    assert_eq!(ops.estimated_bytes(), sampled);
+    assert!(ops.sampling_error() > 0);
+    assert!(sampled.max(exact) - sampled.min(exact) <= ops.sampling_error());
+    assert_eq!(strings.size_of(&mut sampled_ops()), sampled);
+
+    // Small collections are measured in full.
+    let mut ops = sampled_ops();
+    assert_eq!(
+        strings[..1000].size_of(&mut ops),
+        strings[..1000].size_of(&mut MallocSizeOfOps::builder().build())
+    );
+    assert_eq!(ops.sampling_error(), 0);
+}
+
+#[test]
+fn test_budgets() {
+    // A long chain of boxes.
+    struct Node(Option<Box<Node>>);
+    impl MallocSizeOf for Node {
+        fn size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
+            self.0.size_of(ops)
+        }
+    }
+    let mut list = Node(None);
+    for _ in 0..100 {
+        list = Node(Some(Box::new(list)));
+    }
+
+    let mut ops = MallocSizeOfOps::builder()
+        .size_of_op(fixed_size::<1>)
+        .max_allocator_calls(10)
+        .build();
+    assert_eq!(list.size_of(&mut ops), 10);
+    assert!(ops.budget_exhausted());
+    assert!(ops.stopped_at().unwrap().ends_with("Node"));
+
+    let strings: Vec<String> = (1..=100).map(|i| "x".repeat(i)).collect();
+    let mut ops = MallocSizeOfOps::builder()
+        .size_of_op(fixed_size::<1>)
+        .max_allocator_calls(10)
+        .build();
+    assert_eq!(strings.size_of(&mut ops), 10);
+    assert!(ops.stopped_at().unwrap().ends_with("u8"));
+
+    let mut ops = MallocSizeOfOps::builder()
+        .size_of_op(fixed_size::<1>)
+        .max_allocator_calls(101)
+        .build();
+    assert_eq!(strings.size_of(&mut ops), 101);
+    assert!(!ops.budget_exhausted());
+
+    #[cfg(feature = "std")]
+    {
+        let mut ops = MallocSizeOfOps::builder()
+            .deadline(std::time::Instant::now())
+            .build();
+        assert_eq!(strings.size_of(&mut ops), 0);
+        assert!(ops.budget_exhausted());
+    }
+}
+
+#[test]
+fn test_batch_size_of() {
+    use core::sync::atomic::Ordering;
+
+    static SINGLE_CALLS: AtomicUsize = AtomicUsize::new(0);
+    static BATCH_CALLS: AtomicUsize = AtomicUsize::new(0);
+    extern "C" fn size_of_op(_: *const c_void) -> usize {
+        SINGLE_CALLS.fetch_add(1, Ordering::Relaxed);
+        16
+    }
+    unsafe extern "C" fn batch_size_of_op(_: *const *const c_void, sizes: *mut usize, len: usize) {
+        BATCH_CALLS.fetch_add(1, Ordering::Relaxed);
+        for i in 0..len {
+            *sizes.add(i) = 16;
+        }
+    }
+
+    let boxes: Vec<Box<u64>> = (0..200).map(Box::new).collect();
+    let strings: VecDeque<String> = (1..=100).map(|i| "x".repeat(i)).collect();
+
+    let mut ops = MallocSizeOfOps::builder().size_of_op(size_of_op).build();
+    let expected = boxes.size_of(&mut ops) + strings.size_of(&mut ops);
+    assert_eq!(SINGLE_CALLS.swap(0, Ordering::Relaxed), 1 + 200 + 100);
+
+    let mut ops = MallocSizeOfOps::builder()
+        .size_of_op(size_of_op)
+        .batch_size_of_op(batch_size_of_op)
+        .build();
+    assert_eq!(
+        boxes.size_of(&mut ops) + strings.size_of(&mut ops),
+        expected
+    );
+    assert_eq!(SINGLE_CALLS.load(Ordering::Relaxed), 1);
+    assert_eq!(BATCH_CALLS.load(Ordering::Relaxed), 4 + 2);
+}
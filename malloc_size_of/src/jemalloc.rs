@@ -0,0 +1,270 @@
+// Copyright 2016-2017 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Heap size measurement for jemalloc, as provided by `tikv-jemalloc-sys`.
+//! This is only useful if jemalloc is the global allocator, e.g. via the
+//! `tikv-jemallocator` crate.
+
+use crate::{MallocSizeOfOps, MallocSizeOfOpsBuilder};
+use core::ffi::c_void;
+use core::mem::size_of;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use tikv_jemalloc_sys as ffi;
+
+/// Get the size of the allocation at `ptr`, which must have been allocated by
+/// jemalloc.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn usable_size(ptr: *const c_void) -> usize {
+    ffi::malloc_usable_size(ptr)
+}
+
+/// Get the size of the allocation containing `ptr`, which may be an interior
+/// pointer, and must point into an allocation made by jemalloc.
+///
+/// jemalloc can only look up a pointer if it points into a small allocation,
+/// or into the first or last page of a large one. Each large allocation has
+/// its own extent (run of pages), so other pointers are handled by searching
+/// backwards for the extent's first page, and using the extent's size. No
+/// allocation is larger than the largest large size class, so the search stops
+/// after that many bytes' worth of pages, and 0 is returned if no extent is
+/// found.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn enclosing_usable_size(ptr: *const c_void) -> usize {
+    let mallctls = Mallctls::get();
+    let mut addr = ptr as usize;
+    for _ in 0..=mallctls.large_max / mallctls.page_size {
+        let extent = extent_info(&mallctls, addr);
+        if extent.size != 0 {
+            if extent.nregs != 1 || extent.size < mallctls.large_min {
+                // A slab of small allocations, where every page can be looked
+                // up, so this is the first iteration.
+                return ffi::malloc_usable_size(ptr);
+            }
+            // Large extents have an extra page when jemalloc randomizes the
+            // offsets of large allocations.
+            let extra = if mallctls.cache_oblivious {
+                mallctls.page_size
+            } else {
+                0
+            };
+            return extent.size - extra;
+        }
+        match (addr & !(mallctls.page_size - 1)).checked_sub(mallctls.page_size) {
+            Some(page) if page != 0 => addr = page,
+            _ => break,
+        }
+    }
+    0
+}
+
+/// The jemalloc options and size classes that `enclosing_usable_size` needs,
+/// and the MIB (the numeric form of the name) of
+/// `experimental.utilization.query`. Looking a mallctl up by name is slow, so
+/// they are only looked up once.
+struct Mallctls {
+    page_size: usize,
+    large_min: usize,
+    large_max: usize,
+    cache_oblivious: bool,
+    query_mib: [usize; 3],
+}
+
+// The looked-up `Mallctls`, valid once `PAGE_SIZE` isn't 0. Threads racing to
+// look them up store the same values.
+static PAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
+static LARGE_MIN: AtomicUsize = AtomicUsize::new(0);
+static LARGE_MAX: AtomicUsize = AtomicUsize::new(0);
+static CACHE_OBLIVIOUS: AtomicBool = AtomicBool::new(false);
+static QUERY_MIB: [AtomicUsize; 3] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+
+impl Mallctls {
+    fn get() -> Mallctls {
+        let page_size = PAGE_SIZE.load(Ordering::Acquire);
+        if page_size != 0 {
+            return Mallctls {
+                page_size,
+                large_min: LARGE_MIN.load(Ordering::Relaxed),
+                large_max: LARGE_MAX.load(Ordering::Relaxed),
+                cache_oblivious: CACHE_OBLIVIOUS.load(Ordering::Relaxed),
+                query_mib: [
+                    QUERY_MIB[0].load(Ordering::Relaxed),
+                    QUERY_MIB[1].load(Ordering::Relaxed),
+                    QUERY_MIB[2].load(Ordering::Relaxed),
+                ],
+            };
+        }
+
+        let mut query_mib = [0; 3];
+        let mut query_mib_len = query_mib.len();
+        let result = unsafe {
+            ffi::mallctlnametomib(
+                b"experimental.utilization.query\0".as_ptr().cast(),
+                query_mib.as_mut_ptr(),
+                &mut query_mib_len,
+            )
+        };
+        assert!(
+            result == 0 && query_mib_len == query_mib.len(),
+            "failed to look up experimental.utilization.query"
+        );
+        let mallctls = Mallctls {
+            page_size: read_mallctl(b"arenas.page\0"),
+            large_min: read_mallctl(b"arenas.lextent.0.size\0"),
+            large_max: largest_large_size(),
+            cache_oblivious: read_mallctl(b"opt.cache_oblivious\0"),
+            query_mib,
+        };
+        LARGE_MIN.store(mallctls.large_min, Ordering::Relaxed);
+        LARGE_MAX.store(mallctls.large_max, Ordering::Relaxed);
+        CACHE_OBLIVIOUS.store(mallctls.cache_oblivious, Ordering::Relaxed);
+        for (mib, &value) in QUERY_MIB.iter().zip(&mallctls.query_mib) {
+            mib.store(value, Ordering::Relaxed);
+        }
+        PAGE_SIZE.store(mallctls.page_size, Ordering::Release);
+        mallctls
+    }
+}
+
+/// What `experimental.utilization.query` reports about an extent.
+#[repr(C)]
+struct ExtentInfo {
+    slabcur_addr: *mut c_void,
+    nfree: usize,
+    nregs: usize,
+    size: usize,
+    bin_nfree: usize,
+    bin_nregs: usize,
+}
+
+/// Get jemalloc's view of the extent containing `addr`. The size is 0 if
+/// jemalloc can't find one.
+unsafe fn extent_info(mallctls: &Mallctls, addr: usize) -> ExtentInfo {
+    let mut info = ExtentInfo {
+        slabcur_addr: ptr::null_mut(),
+        nfree: 0,
+        nregs: 0,
+        size: 0,
+        bin_nfree: 0,
+        bin_nregs: 0,
+    };
+    let mut info_len = size_of::<ExtentInfo>();
+    let mut ptr = addr as *const c_void;
+    let result = ffi::mallctlbymib(
+        mallctls.query_mib.as_ptr(),
+        mallctls.query_mib.len(),
+        &mut info as *mut ExtentInfo as *mut c_void,
+        &mut info_len,
+        &mut ptr as *mut *const c_void as *mut c_void,
+        size_of::<*const c_void>(),
+    );
+    assert_eq!(result, 0, "experimental.utilization.query failed");
+    info
+}
+
+/// Read a value, such as a configuration option, from jemalloc.
+fn read_mallctl<T: Copy + Default>(name: &[u8]) -> T {
+    let mut value = T::default();
+    let mut len = size_of::<T>();
+    let result = unsafe {
+        ffi::mallctl(
+            name.as_ptr().cast(),
+            &mut value as *mut T as *mut c_void,
+            &mut len,
+            ptr::null_mut(),
+            0,
+        )
+    };
+    assert_eq!(result, 0, "failed to read a jemalloc option");
+    value
+}
+
+/// Read `arenas.lextent.<n>.size` for the last of the `arenas.nlextents` large
+/// size classes.
+fn largest_large_size() -> usize {
+    let nlextents: u32 = read_mallctl(b"arenas.nlextents\0");
+    let mut mib = [0; 4];
+    let mut mib_len = mib.len();
+    let mut size = 0usize;
+    let mut len = size_of::<usize>();
+    let result = unsafe {
+        ffi::mallctlnametomib(
+            b"arenas.lextent.0.size\0".as_ptr().cast(),
+            mib.as_mut_ptr(),
+            &mut mib_len,
+        )
+    };
+    assert!(
+        result == 0 && mib_len == mib.len(),
+        "failed to look up arenas.lextent.0.size"
+    );
+    mib[2] = nlextents as usize - 1;
+    let result = unsafe {
+        ffi::mallctlbymib(
+            mib.as_ptr(),
+            mib.len(),
+            &mut size as *mut usize as *mut c_void,
+            &mut len,
+            ptr::null_mut(),
+            0,
+        )
+    };
+    assert_eq!(result, 0, "failed to read the largest large size class");
+    size
+}
+
+/// A builder for `MallocSizeOfOps` that uses [`usable_size`] and
+/// [`enclosing_usable_size`].
+pub fn builder() -> MallocSizeOfOpsBuilder {
+    MallocSizeOfOps::builder()
+        .size_of_op(usable_size)
+        .enclosing_size_of_op(enclosing_usable_size)
+}
+
+#[test]
+fn test_usable_size() {
+    unsafe {
+        for size in [100, 2500] {
+            let small = ffi::malloc(size) as *const u8;
+            let small_size = usable_size(small.cast());
+            assert!(small_size >= size);
+            assert_eq!(
+                enclosing_usable_size(small.add(size - 1).cast()),
+                small_size
+            );
+            ffi::free(small as *mut c_void);
+        }
+
+        let large = ffi::malloc(1 << 20) as *const u8;
+        let large_size = usable_size(large.cast());
+        assert!(large_size >= 1 << 20);
+        for offset in [1, 5000, 1 << 19, (1 << 20) - 1] {
+            assert_eq!(
+                enclosing_usable_size(large.add(offset).cast()),
+                large_size,
+                "{}",
+                offset
+            );
+        }
+        ffi::free(large as *mut c_void);
+    }
+
+    // Later lookups reuse the first one.
+    let (first, second) = (Mallctls::get(), Mallctls::get());
+    assert_eq!(first.query_mib, second.query_mib);
+    assert_eq!(second.page_size, read_mallctl::<usize>(b"arenas.page\0"));
+    assert_eq!(second.large_min, first.large_min);
+    assert!(second.large_max >= 1 << 20);
+    assert_eq!(second.large_max, first.large_max);
+}
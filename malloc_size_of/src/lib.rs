@@ -35,6 +35,26 @@
 //! - It allows handling of types like `Rc` and `Arc` by providing traits that
 //!   are different to the ones for non-graph structures.
 //!
+//! ## Allocator support
+//!
+//! `MallocSizeOfOps` needs to be given functions that ask the heap allocator
+//! for the size of an allocation. Optional modules provide these:
+//!
+//! - `tracking` (with the `tracking` feature) provides a `GlobalAlloc`
+//!   wrapper that records the size of every live allocation itself, and so
+//!   works with any underlying allocator.
+//! - `glibc` (with the `glibc` feature, on Linux) uses glibc's
+//!   `malloc_usable_size`, for programs using Rust's `System` allocator.
+//! - `jemalloc` (with the `jemalloc` feature) uses jemalloc, including its
+//!   support for interior pointers.
+//! - `mimalloc` (with the `mimalloc` feature) uses mimalloc.
+//!
+//! The `rayon` feature adds `MallocParSizeOf`, which measures large
+//! collections on rayon's worker threads.
+//!
+//! Without any of these, `MallocSizeOfOps::builder().build()` gives
+//! operations that estimate sizes from the layouts and capacities of values.
+//!
 //! ## Suggested usage
 //!
 //! - When possible, use the `MallocSizeOf` trait. (Deriving support is
@@ -54,23 +74,80 @@
 //!   trait.
 //! - If an `Rc` or `Arc` should be measured only if it hasn't been seen
 //!   before, it should be measured via the `MallocConditionalSizeOf` trait.
+//!   This needs a `have_seen_ptr` operation, and
+//!   `MallocSizeOfOps::with_seen_ptrs` provides a built-in one.
+//! - `Weak` pointers keep their `Rc` or `Arc` allocation alive, and can be
+//!   measured via the `MallocConditionalSizeOf` trait. They share seen
+//!   pointers with `Rc` and `Arc`, so an allocation is only counted once.
 //! - Using universal function call syntax is a good idea when measuring boxed
 //!   fields in structs, because it makes it clear that the Box is being
 //!   measured as well as the thing it points to. E.g.
 //!   `<Box<_> as MallocSizeOf>::size_of(field, ops)`.
 #![cfg_attr(not(feature = "std"), no_std)]
 extern crate alloc;
+#[cfg(all(feature = "glibc", target_os = "linux", target_env = "gnu"))]
+pub mod glibc;
 mod impls;
+#[cfg(feature = "jemalloc")]
+pub mod jemalloc;
+#[cfg(feature = "mimalloc")]
+pub mod mimalloc;
+#[cfg(feature = "rayon")]
+pub mod par;
+#[cfg(feature = "rayon")]
+pub use par::MallocParSizeOf;
+#[cfg(feature = "tracking")]
+#[clippy::msrv = "1.66"]
+pub mod tracking;
 
 use alloc::boxed::Box;
+use core::alloc::Layout;
+use core::cell::Cell;
 use core::ffi::c_void;
 
+#[cfg(not(feature = "std"))]
+use alloc::collections::BTreeSet as PtrSet;
+#[cfg(feature = "std")]
+use std::collections::HashSet as PtrSet;
+#[cfg(feature = "std")]
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+#[cfg(feature = "std")]
+use std::time::Instant;
+
 /// Trait for measuring the "deep" heap usage of a data structure. This is the
 /// most commonly-used of the traits.
 pub trait MallocSizeOf {
     /// Measure the heap usage of all descendant heap-allocated structures, but
     /// not the space taken up by the value itself.
     fn size_of(&self, ops: &mut MallocSizeOfOps) -> usize;
+
+    /// Do values of this type never own heap memory, so that `size_of`
+    /// always returns 0? Containers check this to skip measuring their
+    /// elements, e.g. to measure a `Vec<u8>` in constant time. This is
+    /// implemented by `malloc_size_of_is_0!` and by the derive, and is a
+    /// function rather than an associated constant so that `dyn MallocSizeOf`
+    /// can still be used. It must only return `true` when it is known at
+    /// compile time.
+    #[inline(always)]
+    fn never_owns_heap() -> bool
+    where
+        Self: Sized,
+    {
+        false
+    }
+
+    /// Measure the heap usage of all the values in `items`, which is what
+    /// containers such as `Vec` and slices do. Like `Hash::hash_slice`, this
+    /// can be overridden to measure many values at once, e.g. with a batched
+    /// size op.
+    fn size_of_slice(items: &[Self], ops: &mut MallocSizeOfOps) -> usize
+    where
+        Self: Sized,
+    {
+        impls::size_of_elems(items.iter(), items.len(), ops, |item, ops| {
+            item.size_of(ops)
+        })
+    }
 }
 
 /// Trait for measuring the "shallow" heap usage of a container.
@@ -116,24 +193,154 @@ pub trait MallocConditionalShallowSizeOf {
 /// A C function that takes a pointer to a heap allocation and returns its size.
 type VoidPtrToSizeFn = unsafe extern "C" fn(ptr: *const c_void) -> usize;
 
+/// A C function that takes `len` pointers to heap allocations and writes their
+/// sizes to `sizes`.
+type VoidPtrsToSizesFn =
+    unsafe extern "C" fn(ptrs: *const *const c_void, sizes: *mut usize, len: usize);
+
+/// A closure that takes a pointer to a heap allocation and returns its size.
+type VoidPtrToSizeClosure = dyn Fn(*const c_void) -> usize;
+
+/// Like `VoidPtrToSizeClosure`, but can be shared between threads.
+#[cfg(feature = "std")]
+type VoidPtrToSizeSyncClosure = dyn Fn(*const c_void) -> usize + Send + Sync;
+
 /// A closure implementing a stateful predicate on pointers.
 type VoidPtrToBoolFnMut = dyn FnMut(*const c_void) -> bool;
 
+/// A set of pointers, used as a built-in `have_seen_ptr` operation. This is a
+/// `HashSet` when the `std` feature is enabled, and a `BTreeSet` otherwise.
+#[derive(Default)]
+pub struct SeenPtrs {
+    set: PtrSet<usize>,
+}
+
+impl SeenPtrs {
+    /// Create an empty set.
+    pub fn new() -> Self {
+        SeenPtrs::default()
+    }
+
+    /// The number of distinct pointers seen so far.
+    pub fn len(&self) -> usize {
+        self.set.len()
+    }
+
+    /// Have no pointers been seen so far?
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty()
+    }
+
+    /// Forget all the pointers seen so far, e.g. between measurement passes.
+    pub fn clear(&mut self) {
+        self.set.clear()
+    }
+
+    /// Check if `ptr` has been seen before, and remember it for next time.
+    fn have_seen_ptr(&mut self, ptr: *const c_void) -> bool {
+        !self.set.insert(ptr as usize)
+    }
+}
+
+/// The two forms a `size_of_op` or `enclosing_size_of_op` can take.
+enum SizeOfOp {
+    Fn(VoidPtrToSizeFn),
+    Closure(Box<VoidPtrToSizeClosure>),
+}
+
+impl SizeOfOp {
+    unsafe fn call(&self, ptr: *const c_void) -> usize {
+        match *self {
+            SizeOfOp::Fn(f) => f(ptr),
+            SizeOfOp::Closure(ref f) => f(ptr),
+        }
+    }
+}
+
+/// The forms a `have_seen_ptr_op` can take.
+enum HaveSeenPtrOp {
+    Fn(Box<VoidPtrToBoolFnMut>),
+    SeenPtrs(SeenPtrs),
+    #[cfg(feature = "std")]
+    Shared(Arc<Mutex<SeenPtrs>>),
+}
+
+/// How to measure a sample of the elements of large collections.
+struct Sampling {
+    /// Collections with more elements than this are sampled.
+    threshold: usize,
+
+    /// Roughly how many elements to measure in a sampled collection.
+    sample_size: usize,
+
+    /// The state of the SplitMix64 generator that picks the samples.
+    rng: u64,
+}
+
+impl Sampling {
+    fn next_u64(&mut self) -> u64 {
+        self.rng = self.rng.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+}
+
 /// Operations used when measuring heap usage of data structures.
 pub struct MallocSizeOfOps {
-    /// A function that returns the size of a heap allocation.
-    size_of_op: VoidPtrToSizeFn,
+    /// A function that returns the size of a heap allocation. Optional
+    /// because not all environments have a way to ask the allocator. If it's
+    /// not provided, all memory measurements will be estimates computed from
+    /// the layouts and capacities of values.
+    size_of_op: Option<SizeOfOp>,
 
     /// Like `size_of_op`, but can take an interior pointer. Optional because
     /// not all allocators support this operation. If it's not provided, some
     /// memory measurements will actually be computed estimates rather than
     /// real and accurate measurements.
-    enclosing_size_of_op: Option<VoidPtrToSizeFn>,
+    enclosing_size_of_op: Option<SizeOfOp>,
+
+    /// Like `size_of_op`, but takes many pointers at once, to save the cost
+    /// of a call per allocation. Optional, and only used along with a
+    /// `size_of_op`.
+    batch_size_of_op: Option<VoidPtrsToSizesFn>,
 
     /// Check if a pointer has been seen before, and remember it for next time.
     /// Useful when measuring `Rc`s and `Arc`s. Optional, because many places
     /// don't need it.
-    have_seen_ptr_op: Option<Box<VoidPtrToBoolFnMut>>,
+    have_seen_ptr_op: Option<HaveSeenPtrOp>,
+
+    /// The number of bytes measured by asking the allocator so far.
+    measured_bytes: Cell<usize>,
+
+    /// The number of bytes estimated so far, because the allocator couldn't
+    /// be asked.
+    estimated_bytes: Cell<usize>,
+
+    /// If set, only a sample of the elements of large collections is
+    /// measured, and their total is extrapolated.
+    sampling: Option<Sampling>,
+
+    /// The sum of the variances of all the extrapolated totals so far.
+    sampling_variance: f64,
+
+    /// How many more times the allocator may be asked for a size, if that is
+    /// limited.
+    allocator_calls_left: Option<Cell<usize>>,
+
+    /// When to stop measuring, if there is a time limit.
+    #[cfg(feature = "std")]
+    deadline: Option<Instant>,
+
+    /// The name of the type that was being measured when the budget ran out,
+    /// if it has.
+    stopped_at: Cell<Option<&'static str>>,
+
+    /// The operations these were created from, if any, which parallel
+    /// measurement uses to create operations for worker threads.
+    #[cfg(feature = "rayon")]
+    shared: Option<SharedMallocSizeOfOps>,
 }
 
 impl MallocSizeOfOps {
@@ -142,48 +349,216 @@ impl MallocSizeOfOps {
         malloc_enclosing_size_of: Option<VoidPtrToSizeFn>,
         have_seen_ptr: Option<Box<VoidPtrToBoolFnMut>>,
     ) -> Self {
-        MallocSizeOfOps {
-            size_of_op: size_of,
-            enclosing_size_of_op: malloc_enclosing_size_of,
-            have_seen_ptr_op: have_seen_ptr,
+        MallocSizeOfOpsBuilder {
+            size_of_op: Some(SizeOfOp::Fn(size_of)),
+            enclosing_size_of_op: malloc_enclosing_size_of.map(SizeOfOp::Fn),
+            have_seen_ptr_op: have_seen_ptr.map(HaveSeenPtrOp::Fn),
+            ..MallocSizeOfOpsBuilder::default()
         }
+        .build()
     }
 
-    /// Check if an allocation is empty. This relies on knowledge of how Rust
-    /// handles empty allocations, which may change in the future.
-    fn is_empty<T: ?Sized>(ptr: *const T) -> bool {
-        // The correct condition is this:
-        //   `ptr as usize <= ::std::mem::align_of::<T>()`
-        // But we can't call align_of() on a ?Sized T. So we approximate it
-        // with the following. 256 is large enough that it should always be
-        // larger than the required alignment, but small enough that it is
-        // always in the first page of memory and therefore not a legitimate
-        // address.
-        ptr as *const usize as usize <= 256
+    /// Like `new`, but uses a built-in [`SeenPtrs`] set for `have_seen_ptr`.
+    pub fn with_seen_ptrs(
+        size_of: VoidPtrToSizeFn,
+        malloc_enclosing_size_of: Option<VoidPtrToSizeFn>,
+    ) -> Self {
+        MallocSizeOfOpsBuilder {
+            size_of_op: Some(SizeOfOp::Fn(size_of)),
+            enclosing_size_of_op: malloc_enclosing_size_of.map(SizeOfOp::Fn),
+            ..MallocSizeOfOpsBuilder::default()
+        }
+        .track_seen_ptrs()
+        .build()
+    }
+
+    /// Start building a `MallocSizeOfOps` with named options, rather than
+    /// passing them all to `new`.
+    pub fn builder() -> MallocSizeOfOpsBuilder {
+        MallocSizeOfOpsBuilder::default()
     }
 
-    /// Call `size_of_op` on `ptr`, first checking that the allocation isn't
-    /// empty, because some types (such as `Vec`) utilize empty allocations.
+    /// Is a `size_of_op` available? If not, every measurement is an estimate.
+    pub fn has_malloc_size_of(&self) -> bool {
+        self.size_of_op.is_some()
+    }
+
+    /// Is `ptr` the dangling pointer of an empty allocation? Rust gives an
+    /// empty allocation the address of its alignment, but we can't call
+    /// `align_of` on a `?Sized` `T`. An allocator never hands out an address
+    /// in the first page, so that is used instead. Callers that know the
+    /// layout should use `malloc_layout_size_of`, which doesn't need this.
+    fn is_dangling<T: ?Sized>(ptr: *const T) -> bool {
+        ptr as *const u8 as usize <= 4096
+    }
+
+    /// Call `size_of_op` on `ptr`, which must either be the dangling pointer
+    /// of an empty allocation or point to a live `T` at the start of a heap
+    /// allocation, such as the contents of a `Box<T>`. Dangling pointers are
+    /// detected from their address, and 0 is returned without calling
+    /// `size_of_op`. If there is no `size_of_op`, the size of the pointee is
+    /// used as an estimate. When the layout of the allocation is known, prefer
+    /// `malloc_layout_size_of` or `malloc_buffer_size_of`, which are exact.
     #[allow(clippy::missing_safety_doc)]
     pub unsafe fn malloc_size_of<T: ?Sized>(&self, ptr: *const T) -> usize {
-        if MallocSizeOfOps::is_empty(ptr) {
+        if MallocSizeOfOps::is_dangling(ptr) || !self.spend_budget::<T>() {
             0
+        } else if let Some(ref size_of_op) = self.size_of_op {
+            self.record_measured(size_of_op.call(ptr as *const c_void))
         } else {
-            (self.size_of_op)(ptr as *const c_void)
+            self.record_estimate(Layout::for_value(&*ptr).size())
         }
     }
 
+    /// Like `malloc_size_of`, but for a heap allocation whose layout is given
+    /// by `layout` rather than by `T`. A zero-sized `layout` means nothing was
+    /// allocated. If there is no `size_of_op`, the size of `layout` is used as
+    /// an estimate.
+    #[allow(clippy::missing_safety_doc)]
+    pub unsafe fn malloc_layout_size_of<T: ?Sized>(&self, ptr: *const T, layout: Layout) -> usize {
+        if layout.size() == 0 || !self.spend_budget::<T>() {
+            0
+        } else if let Some(ref size_of_op) = self.size_of_op {
+            self.record_measured(size_of_op.call(ptr as *const c_void))
+        } else {
+            self.record_estimate(layout.size())
+        }
+    }
+
+    /// Like `malloc_size_of`, but for a heap buffer with room for `capacity`
+    /// values of type `T`, such as the one backing a `Vec<T>`. Nothing is
+    /// allocated when `capacity` is 0 or `T` is zero-sized. If there is no
+    /// `size_of_op`, the size of the buffer is used as an estimate.
+    #[allow(clippy::missing_safety_doc)]
+    pub unsafe fn malloc_buffer_size_of<T>(&self, ptr: *const T, capacity: usize) -> usize {
+        match Layout::array::<T>(capacity) {
+            Ok(layout) => self.malloc_layout_size_of(ptr, layout),
+            Err(_) => 0,
+        }
+    }
+
+    /// Is a `batch_size_of_op` available?
+    pub fn has_malloc_batch_size_of(&self) -> bool {
+        self.batch_size_of_op.is_some() && self.size_of_op.is_some()
+    }
+
+    /// Call `batch_size_of_op`, which must be available, on `ptrs`, and
+    /// return the sum of the sizes. Each pointer must point to the start of
+    /// a live, non-empty heap allocation holding a `T`. Each pointer counts
+    /// as an allocator call towards the budget, and any pointers beyond the
+    /// budget aren't measured.
+    #[allow(clippy::missing_safety_doc)]
+    pub unsafe fn malloc_batch_size_of<T: ?Sized>(&self, ptrs: &[*const c_void]) -> usize {
+        const CHUNK: usize = 64;
+        let batch_size_of_op = self.batch_size_of_op.unwrap();
+        let mut n = 0;
+        for chunk in ptrs.chunks(CHUNK) {
+            if !self.spend_budget::<T>() {
+                break;
+            }
+            // `spend_budget` spent one allocator call, so spend the rest.
+            let mut len = chunk.len();
+            if let Some(ref left) = self.allocator_calls_left {
+                len = len.min(left.get() + 1);
+                left.set(left.get() + 1 - len);
+                if len < chunk.len() {
+                    self.stopped_at.set(Some(core::any::type_name::<T>()));
+                }
+            }
+            let mut sizes = [0; CHUNK];
+            batch_size_of_op(chunk.as_ptr(), sizes.as_mut_ptr(), len);
+            n += sizes[..len].iter().sum::<usize>();
+        }
+        self.record_measured(n)
+    }
+
     /// Is an `enclosing_size_of_op` available?
     pub fn has_malloc_enclosing_size_of(&self) -> bool {
         self.enclosing_size_of_op.is_some()
     }
 
     /// Call `enclosing_size_of_op`, which must be available, on `ptr`, which
-    /// must not be empty.
+    /// must point into a live heap allocation. `T` must not be zero-sized,
+    /// because pointers to zero-sized values may be dangling.
     #[allow(clippy::missing_safety_doc)]
     pub unsafe fn malloc_enclosing_size_of<T>(&self, ptr: *const T) -> usize {
-        assert!(!MallocSizeOfOps::is_empty(ptr));
-        (self.enclosing_size_of_op.unwrap())(ptr as *const c_void)
+        assert!(core::mem::size_of::<T>() != 0);
+        if !self.spend_budget::<T>() {
+            return 0;
+        }
+        let enclosing_size_of_op = self.enclosing_size_of_op.as_ref().unwrap();
+        self.record_measured(enclosing_size_of_op.call(ptr as *const c_void))
+    }
+
+    fn record_measured(&self, size: usize) -> usize {
+        self.measured_bytes.set(self.measured_bytes.get() + size);
+        size
+    }
+
+    /// Record that `size` bytes were estimated rather than measured by asking
+    /// the allocator, and return `size`. Implementations that compute
+    /// estimates should pass them through this.
+    pub fn record_estimate(&self, size: usize) -> usize {
+        self.estimated_bytes.set(self.estimated_bytes.get() + size);
+        size
+    }
+
+    /// The number of bytes measured so far that came from asking the
+    /// allocator.
+    pub fn measured_bytes(&self) -> usize {
+        self.measured_bytes.get()
+    }
+
+    /// The number of bytes measured so far that were estimated, because the
+    /// allocator couldn't be asked.
+    pub fn estimated_bytes(&self) -> usize {
+        self.estimated_bytes.get()
+    }
+
+    /// Check the budget before measuring an allocation holding a `T`, and
+    /// spend an allocator call if the allocator is going to be asked. Returns
+    /// false, and records where measurement stopped, if the budget has run
+    /// out.
+    fn spend_budget<T: ?Sized>(&self) -> bool {
+        if self.stopped_at.get().is_some() {
+            return false;
+        }
+        let mut exhausted = false;
+        if let (Some(left), true) = (&self.allocator_calls_left, self.size_of_op.is_some()) {
+            match left.get().checked_sub(1) {
+                Some(n) => left.set(n),
+                None => exhausted = true,
+            }
+        }
+        #[cfg(feature = "std")]
+        if let Some(deadline) = self.deadline {
+            exhausted |= Instant::now() >= deadline;
+        }
+        if exhausted {
+            self.stopped_at.set(Some(core::any::type_name::<T>()));
+        }
+        !exhausted
+    }
+
+    /// Has the budget for this measurement run out? If so, results are
+    /// partial: further allocations are counted as 0 and collections stop
+    /// measuring their elements. Implementations of the traits that walk
+    /// long or deep structures should check this to stop early too.
+    pub fn budget_exhausted(&self) -> bool {
+        self.stopped_at.get().is_some()
+    }
+
+    /// If the budget has run out, the name of the type that was being
+    /// measured at the time.
+    pub fn stopped_at(&self) -> Option<&'static str> {
+        self.stopped_at.get()
+    }
+
+    /// The half-width of an approximate 95% confidence interval for the
+    /// bytes extrapolated from samples so far, which are included in
+    /// `estimated_bytes`. This is 0 if nothing was sampled.
+    pub fn sampling_error(&self) -> usize {
+        (1.96 * sqrt(self.sampling_variance) + 0.5) as usize
     }
 
     /// Call `have_seen_ptr_op` on `ptr`.
@@ -192,6 +567,272 @@ impl MallocSizeOfOps {
             .have_seen_ptr_op
             .as_mut()
             .expect("missing have_seen_ptr_op");
-        have_seen_ptr_op(ptr as *const c_void)
+        match have_seen_ptr_op {
+            HaveSeenPtrOp::Fn(f) => f(ptr as *const c_void),
+            HaveSeenPtrOp::SeenPtrs(seen) => seen.have_seen_ptr(ptr as *const c_void),
+            #[cfg(feature = "std")]
+            HaveSeenPtrOp::Shared(seen) => seen
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .have_seen_ptr(ptr as *const c_void),
+        }
+    }
+
+    /// The built-in [`SeenPtrs`] set, if `have_seen_ptr_op` is using one.
+    pub fn seen_ptrs(&self) -> Option<&SeenPtrs> {
+        match self.have_seen_ptr_op {
+            Some(HaveSeenPtrOp::SeenPtrs(ref seen)) => Some(seen),
+            _ => None,
+        }
+    }
+
+    /// Like `seen_ptrs`, but mutable, e.g. to clear the set between
+    /// measurement passes.
+    pub fn seen_ptrs_mut(&mut self) -> Option<&mut SeenPtrs> {
+        match self.have_seen_ptr_op {
+            Some(HaveSeenPtrOp::SeenPtrs(ref mut seen)) => Some(seen),
+            _ => None,
+        }
+    }
+}
+
+/// A builder for [`MallocSizeOfOps`], created by [`MallocSizeOfOps::builder`].
+#[derive(Default)]
+pub struct MallocSizeOfOpsBuilder {
+    size_of_op: Option<SizeOfOp>,
+    enclosing_size_of_op: Option<SizeOfOp>,
+    batch_size_of_op: Option<VoidPtrsToSizesFn>,
+    have_seen_ptr_op: Option<HaveSeenPtrOp>,
+    sampling: Option<Sampling>,
+    max_allocator_calls: Option<usize>,
+    #[cfg(feature = "std")]
+    deadline: Option<Instant>,
+}
+
+impl MallocSizeOfOpsBuilder {
+    /// Set the function that returns the size of a heap allocation. If this
+    /// isn't set, sizes are estimated from the layouts and capacities of
+    /// values instead, e.g. for tests, or for targets with no way to ask the
+    /// allocator.
+    pub fn size_of_op(mut self, size_of: VoidPtrToSizeFn) -> Self {
+        self.size_of_op = Some(SizeOfOp::Fn(size_of));
+        self
+    }
+
+    /// Like `size_of_op`, but takes a closure, which can carry state such as
+    /// an allocation registry. This costs an indirect call through a trait
+    /// object per allocation, so FFI callers should prefer `size_of_op`.
+    pub fn size_of_closure(mut self, size_of: Box<VoidPtrToSizeClosure>) -> Self {
+        self.size_of_op = Some(SizeOfOp::Closure(size_of));
+        self
+    }
+
+    /// Set the function that returns the size of a heap allocation given an
+    /// interior pointer, if the allocator supports this.
+    pub fn enclosing_size_of_op(mut self, malloc_enclosing_size_of: VoidPtrToSizeFn) -> Self {
+        self.enclosing_size_of_op = Some(SizeOfOp::Fn(malloc_enclosing_size_of));
+        self
+    }
+
+    /// Like `enclosing_size_of_op`, but takes a closure.
+    pub fn enclosing_size_of_closure(
+        mut self,
+        malloc_enclosing_size_of: Box<VoidPtrToSizeClosure>,
+    ) -> Self {
+        self.enclosing_size_of_op = Some(SizeOfOp::Closure(malloc_enclosing_size_of));
+        self
+    }
+
+    /// Set the function that returns the sizes of many heap allocations at
+    /// once, if the allocator supports this. It is used along with the
+    /// `size_of_op` to measure collections of boxes and strings.
+    pub fn batch_size_of_op(mut self, batch_size_of: VoidPtrsToSizesFn) -> Self {
+        self.batch_size_of_op = Some(batch_size_of);
+        self
+    }
+
+    /// Set the closure that checks if a pointer has been seen before, and
+    /// remembers it for next time.
+    pub fn have_seen_ptr_op(mut self, have_seen_ptr: Box<VoidPtrToBoolFnMut>) -> Self {
+        self.have_seen_ptr_op = Some(HaveSeenPtrOp::Fn(have_seen_ptr));
+        self
+    }
+
+    /// Use a built-in [`SeenPtrs`] set for `have_seen_ptr`, instead of a
+    /// closure.
+    pub fn track_seen_ptrs(mut self) -> Self {
+        self.have_seen_ptr_op = Some(HaveSeenPtrOp::SeenPtrs(SeenPtrs::new()));
+        self
+    }
+
+    /// Only measure a sample of the elements of collections with more than
+    /// `threshold` elements, and extrapolate their total from it. Between
+    /// `sample_size` and `2 * sample_size` evenly spaced elements are
+    /// measured, starting at an offset picked by a random number generator
+    /// seeded with `seed`, so results are reproducible. Extrapolated totals
+    /// count as estimates, and `MallocSizeOfOps::sampling_error` gives their
+    /// margin of error. Elements measured conditionally, such as `Arc`s, can
+    /// skew the extrapolation.
+    pub fn sample_elements(mut self, threshold: usize, sample_size: usize, seed: u64) -> Self {
+        assert!(0 < sample_size && sample_size <= threshold);
+        self.sampling = Some(Sampling {
+            threshold,
+            sample_size,
+            rng: seed,
+        });
+        self
+    }
+
+    /// Stop measuring after the allocator has been asked for `n` sizes.
+    /// Measurement then stops cleanly, and the result is partial; see
+    /// `MallocSizeOfOps::budget_exhausted`. Estimates don't count towards
+    /// this limit.
+    pub fn max_allocator_calls(mut self, n: usize) -> Self {
+        self.max_allocator_calls = Some(n);
+        self
+    }
+
+    /// Stop measuring once `deadline` has passed. Measurement then stops
+    /// cleanly, and the result is partial; see
+    /// `MallocSizeOfOps::budget_exhausted`. The clock is read once per
+    /// allocation measured.
+    #[cfg(feature = "std")]
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Create the `MallocSizeOfOps`.
+    pub fn build(self) -> MallocSizeOfOps {
+        MallocSizeOfOps {
+            size_of_op: self.size_of_op,
+            enclosing_size_of_op: self.enclosing_size_of_op,
+            batch_size_of_op: self.batch_size_of_op,
+            have_seen_ptr_op: self.have_seen_ptr_op,
+            measured_bytes: Cell::new(0),
+            estimated_bytes: Cell::new(0),
+            sampling: self.sampling,
+            sampling_variance: 0.0,
+            allocator_calls_left: self.max_allocator_calls.map(Cell::new),
+            #[cfg(feature = "std")]
+            deadline: self.deadline,
+            stopped_at: Cell::new(None),
+            #[cfg(feature = "rayon")]
+            shared: None,
+        }
+    }
+}
+
+/// The two forms a size op can take in `SharedMallocSizeOfOps`.
+#[cfg(feature = "std")]
+#[derive(Clone)]
+enum SharedSizeOfOp {
+    Fn(VoidPtrToSizeFn),
+    Closure(Arc<VoidPtrToSizeSyncClosure>),
+}
+
+#[cfg(feature = "std")]
+impl SharedSizeOfOp {
+    fn to_op(&self) -> SizeOfOp {
+        match *self {
+            SharedSizeOfOp::Fn(f) => SizeOfOp::Fn(f),
+            SharedSizeOfOp::Closure(ref f) => {
+                let f = f.clone();
+                SizeOfOp::Closure(Box::new(move |ptr| f(ptr)))
+            }
+        }
+    }
+}
+
+/// Operations that can be sent to, and shared between, threads. Each thread
+/// gets its own `MallocSizeOfOps` from [`ops`](Self::ops), and all of them
+/// share one [`SeenPtrs`] set, so that an `Rc` or `Arc` reachable from
+/// several threads' data is only counted once. The measured and estimated
+/// byte counts are kept per `MallocSizeOfOps`.
+///
+/// `MallocSizeOfOps` created this way can also measure collections in
+/// parallel, with the `rayon` feature.
+#[cfg(feature = "std")]
+#[derive(Clone)]
+pub struct SharedMallocSizeOfOps {
+    size_of_op: Option<SharedSizeOfOp>,
+    enclosing_size_of_op: Option<SharedSizeOfOp>,
+    seen_ptrs: Arc<Mutex<SeenPtrs>>,
+}
+
+#[cfg(feature = "std")]
+impl SharedMallocSizeOfOps {
+    /// Create operations from optional size functions. Without a `size_of`
+    /// function, sizes are estimated as with `MallocSizeOfOps`.
+    pub fn new(
+        size_of: Option<VoidPtrToSizeFn>,
+        malloc_enclosing_size_of: Option<VoidPtrToSizeFn>,
+    ) -> Self {
+        SharedMallocSizeOfOps {
+            size_of_op: size_of.map(SharedSizeOfOp::Fn),
+            enclosing_size_of_op: malloc_enclosing_size_of.map(SharedSizeOfOp::Fn),
+            seen_ptrs: Arc::new(Mutex::new(SeenPtrs::new())),
+        }
+    }
+
+    /// Use a thread-safe closure as the `size_of_op`.
+    pub fn with_size_of_closure(mut self, size_of: Arc<VoidPtrToSizeSyncClosure>) -> Self {
+        self.size_of_op = Some(SharedSizeOfOp::Closure(size_of));
+        self
+    }
+
+    /// Use a thread-safe closure as the `enclosing_size_of_op`.
+    pub fn with_enclosing_size_of_closure(
+        mut self,
+        malloc_enclosing_size_of: Arc<VoidPtrToSizeSyncClosure>,
+    ) -> Self {
+        self.enclosing_size_of_op = Some(SharedSizeOfOp::Closure(malloc_enclosing_size_of));
+        self
+    }
+
+    /// Create a `MallocSizeOfOps` for use on the current thread.
+    pub fn ops(&self) -> MallocSizeOfOps {
+        MallocSizeOfOps {
+            size_of_op: self.size_of_op.as_ref().map(SharedSizeOfOp::to_op),
+            enclosing_size_of_op: self
+                .enclosing_size_of_op
+                .as_ref()
+                .map(SharedSizeOfOp::to_op),
+            batch_size_of_op: None,
+            have_seen_ptr_op: Some(HaveSeenPtrOp::Shared(self.seen_ptrs.clone())),
+            measured_bytes: Cell::new(0),
+            estimated_bytes: Cell::new(0),
+            sampling: None,
+            sampling_variance: 0.0,
+            allocator_calls_left: None,
+            deadline: None,
+            stopped_at: Cell::new(None),
+            #[cfg(feature = "rayon")]
+            shared: Some(self.clone()),
+        }
+    }
+
+    /// Lock and return the shared set of seen pointers, e.g. to clear it
+    /// between measurement passes.
+    pub fn seen_ptrs(&self) -> MutexGuard<'_, SeenPtrs> {
+        self.seen_ptrs
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// The square root of a non-negative `x`, by Newton's method, because
+/// `f64::sqrt` needs `std`.
+fn sqrt(x: f64) -> f64 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+    let mut y = if x > 1.0 { x } else { 1.0 };
+    loop {
+        let next = (y + x / y) / 2.0;
+        if next >= y {
+            return y;
+        }
+        y = next;
     }
 }
@@ -0,0 +1,49 @@
+// Copyright 2016-2017 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Heap size measurement for mimalloc, as provided by `libmimalloc-sys`. This
+//! is only useful if mimalloc is the global allocator, e.g. via the `mimalloc`
+//! crate.
+//!
+//! mimalloc has no reliable way to find an allocation from an interior
+//! pointer: `mi_usable_size` only gives the whole block's size for blocks
+//! allocated without extra alignment, and doesn't say which kind a block is.
+//! So there is no `enclosing_size_of_op`, and types like `HashMap` and
+//! `VecDeque` are measured with estimates.
+
+use crate::{MallocSizeOfOps, MallocSizeOfOpsBuilder};
+use core::ffi::c_void;
+use libmimalloc_sys as ffi;
+
+/// Get the size of the allocation at `ptr`, which must have been allocated by
+/// mimalloc.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn usable_size(ptr: *const c_void) -> usize {
+    ffi::mi_usable_size(ptr)
+}
+
+/// A builder for `MallocSizeOfOps` that uses [`usable_size`].
+pub fn builder() -> MallocSizeOfOpsBuilder {
+    MallocSizeOfOps::builder().size_of_op(usable_size)
+}
+
+#[test]
+fn test_usable_size() {
+    let ops = builder().build();
+    assert!(!ops.has_malloc_enclosing_size_of());
+
+    unsafe {
+        for size in [100, 2500, 1 << 20] {
+            let ptr = ffi::mi_malloc(size);
+            assert!(usable_size(ptr) >= size);
+            ffi::mi_free(ptr);
+        }
+    }
+}
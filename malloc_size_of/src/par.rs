@@ -0,0 +1,218 @@
+// Copyright 2016-2017 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Parallel measurement of large collections, using rayon.
+//!
+//! The elements of a collection are measured on rayon's worker threads, each
+//! with its own `MallocSizeOfOps` created from the same
+//! [`SharedMallocSizeOfOps`](crate::SharedMallocSizeOfOps), so they share one set of seen pointers. The
+//! result is the same as that of the sequential `size_of`.
+//!
+//! ```
+//! use malloc_size_of::{MallocParSizeOf, SharedMallocSizeOfOps};
+//!
+//! let shared = SharedMallocSizeOfOps::new(None, None);
+//! let cache: Vec<String> = (0..1000).map(|i| i.to_string()).collect();
+//! let size = cache.par_size_of(&mut shared.ops());
+//! ```
+
+use crate::{MallocShallowSizeOf, MallocSizeOf, MallocSizeOfOps};
+use rayon_crate::prelude::*;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::hash::{BuildHasher, Hash};
+
+/// Trait for measuring the "deep" heap usage of a collection in parallel.
+pub trait MallocParSizeOf: MallocSizeOf {
+    /// Like `size_of`, but measures the elements in parallel. This only
+    /// happens if `ops` was created by `SharedMallocSizeOfOps::ops`; other
+    /// operations can't be used from other threads, so the elements are
+    /// measured sequentially.
+    fn par_size_of(&self, ops: &mut MallocSizeOfOps) -> usize;
+}
+
+/// Measures `iter`'s items with `f` on rayon's worker threads, and adds the
+/// byte counts of the workers' operations to those of `ops`.
+fn par_measure<I, F>(iter: I, ops: &mut MallocSizeOfOps, f: F) -> usize
+where
+    I: ParallelIterator,
+    F: Fn(I::Item, &mut MallocSizeOfOps) -> usize + Sync + Send,
+{
+    let shared = ops.shared.clone().expect("missing shared ops");
+    let (n, measured, estimated) = iter
+        .map_init(
+            || shared.ops(),
+            |ops, item| {
+                let measured = ops.measured_bytes();
+                let estimated = ops.estimated_bytes();
+                let n = f(item, ops);
+                (
+                    n,
+                    ops.measured_bytes() - measured,
+                    ops.estimated_bytes() - estimated,
+                )
+            },
+        )
+        .reduce(|| (0, 0, 0), |a, b| (a.0 + b.0, a.1 + b.1, a.2 + b.2));
+    ops.record_measured(measured);
+    ops.record_estimate(estimated);
+    n
+}
+
+impl<T: MallocSizeOf + Sync> MallocParSizeOf for [T] {
+    fn par_size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
+        if ops.shared.is_none() || T::never_owns_heap() {
+            return self.size_of(ops);
+        }
+        par_measure(self.par_iter(), ops, |elem, ops| elem.size_of(ops))
+    }
+}
+
+impl<T: MallocSizeOf + Sync> MallocParSizeOf for Vec<T> {
+    fn par_size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
+        if ops.shared.is_none() || T::never_owns_heap() {
+            return self.size_of(ops);
+        }
+        let n = self.shallow_size_of(ops);
+        n + par_measure(self.par_iter(), ops, |elem, ops| elem.size_of(ops))
+    }
+}
+
+impl<T: MallocSizeOf + Sync> MallocParSizeOf for VecDeque<T> {
+    fn par_size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
+        if ops.shared.is_none() || T::never_owns_heap() {
+            return self.size_of(ops);
+        }
+        let n = self.shallow_size_of(ops);
+        n + par_measure(self.par_iter(), ops, |elem, ops| elem.size_of(ops))
+    }
+}
+
+impl<K, V, S> MallocParSizeOf for HashMap<K, V, S>
+where
+    K: Eq + Hash + MallocSizeOf + Sync,
+    V: MallocSizeOf + Sync,
+    S: BuildHasher + Sync,
+{
+    fn par_size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
+        if ops.shared.is_none() || (K::never_owns_heap() && V::never_owns_heap()) {
+            return self.size_of(ops);
+        }
+        let n = self.shallow_size_of(ops);
+        n + par_measure(self.par_iter(), ops, |(k, v), ops| {
+            k.size_of(ops) + v.size_of(ops)
+        })
+    }
+}
+
+impl<T, S> MallocParSizeOf for HashSet<T, S>
+where
+    T: Eq + Hash + MallocSizeOf + Sync,
+    S: BuildHasher + Sync,
+{
+    fn par_size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
+        if ops.shared.is_none() || T::never_owns_heap() {
+            return self.size_of(ops);
+        }
+        let n = self.shallow_size_of(ops);
+        n + par_measure(self.par_iter(), ops, |t, ops| t.size_of(ops))
+    }
+}
+
+impl<K, V> MallocParSizeOf for BTreeMap<K, V>
+where
+    K: Ord + MallocSizeOf + Sync,
+    V: MallocSizeOf + Sync,
+{
+    fn par_size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
+        if ops.shared.is_none() || (K::never_owns_heap() && V::never_owns_heap()) {
+            return self.size_of(ops);
+        }
+        let n = self.shallow_size_of(ops);
+        n + par_measure(self.par_iter(), ops, |(k, v), ops| {
+            k.size_of(ops) + v.size_of(ops)
+        })
+    }
+}
+
+impl<T> MallocParSizeOf for BTreeSet<T>
+where
+    T: Ord + MallocSizeOf + Sync,
+{
+    fn par_size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
+        if ops.shared.is_none() || T::never_owns_heap() {
+            return self.size_of(ops);
+        }
+        let n = self.shallow_size_of(ops);
+        n + par_measure(self.par_iter(), ops, |t, ops| t.size_of(ops))
+    }
+}
+
+#[test]
+fn test_par_size_of() {
+    use crate::MallocConditionalSizeOf;
+    use std::sync::Arc;
+
+    struct Entry {
+        name: String,
+        shared: Arc<Vec<u8>>,
+    }
+
+    impl MallocSizeOf for Entry {
+        fn size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
+            self.name.size_of(ops) + self.shared.conditional_size_of(ops)
+        }
+    }
+
+    let shared_values: Vec<_> = (0..10).map(|i| Arc::new(vec![0; i * 100])).collect();
+    let entries: Vec<Entry> = (0..10_000)
+        .map(|i| Entry {
+            name: "x".repeat(i % 50),
+            shared: shared_values[i % 10].clone(),
+        })
+        .collect();
+    let map: HashMap<usize, String> = (0..10_000).map(|i| (i, i.to_string())).collect();
+
+    let sequential = crate::SharedMallocSizeOfOps::new(None, None);
+    let mut sequential_ops = sequential.ops();
+    let expected = entries.size_of(&mut sequential_ops) + map.size_of(&mut sequential_ops);
+
+    let parallel = crate::SharedMallocSizeOfOps::new(None, None);
+    let mut parallel_ops = parallel.ops();
+    let actual = entries.par_size_of(&mut parallel_ops) + map.par_size_of(&mut parallel_ops);
+
+    assert_eq!(actual, expected);
+    assert_eq!(parallel_ops.estimated_bytes(), expected);
+    assert_eq!(parallel.seen_ptrs().len(), sequential.seen_ptrs().len());
+
+    // Operations that can't be shared fall back to sequential measurement.
+    let mut ops = MallocSizeOfOps::builder().track_seen_ptrs().build();
+    let mut other_ops = MallocSizeOfOps::builder().track_seen_ptrs().build();
+    assert_eq!(
+        entries.par_size_of(&mut ops),
+        entries.size_of(&mut other_ops)
+    );
+}
+
+#[test]
+fn test_par_never_owns_heap() {
+    use crate::impls::Unmeasurable;
+
+    let shared = crate::SharedMallocSizeOfOps::new(None, None);
+    let mut ops = shared.ops();
+    let v: Vec<Unmeasurable> = (0..10_000).map(|_| Unmeasurable).collect();
+    assert_eq!(v.par_size_of(&mut ops), 0);
+    assert_eq!(v[..].par_size_of(&mut ops), 0);
+    let d: VecDeque<Unmeasurable> = v.into_iter().collect();
+    assert_eq!(d.par_size_of(&mut ops), 0);
+    let m: BTreeMap<u32, Unmeasurable> = (0..10_000).map(|i| (i, Unmeasurable)).collect();
+    assert_eq!(m.par_size_of(&mut ops), m.shallow_size_of(&mut ops));
+    let m: HashMap<u32, Unmeasurable> = (0..10_000).map(|i| (i, Unmeasurable)).collect();
+    assert_eq!(m.par_size_of(&mut ops), m.shallow_size_of(&mut ops));
+}
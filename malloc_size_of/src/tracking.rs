@@ -0,0 +1,162 @@
+// Copyright 2016-2017 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A `GlobalAlloc` wrapper that records the size of every live allocation, so
+//! that heap usage can be measured exactly on top of any allocator.
+//!
+//! ```no_run
+//! use malloc_size_of::tracking::TrackingAllocator;
+//! use malloc_size_of::MallocSizeOf;
+//! use std::alloc::System;
+//!
+//! #[global_allocator]
+//! static ALLOCATOR: TrackingAllocator<System> = TrackingAllocator::new(System);
+//!
+//! let mut ops = malloc_size_of::tracking::builder().build();
+//! assert_eq!(Vec::<u8>::with_capacity(100).size_of(&mut ops), 100);
+//! ```
+//!
+//! Every allocation and deallocation takes a global lock, so this is better
+//! suited to tests and diagnostic builds than to production use. It also
+//! needs Rust 1.66 or later.
+
+use crate::{MallocSizeOfOps, MallocSizeOfOpsBuilder};
+use std::alloc::{GlobalAlloc, Layout};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::ffi::c_void;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// The live allocations made through a `TrackingAllocator`, mapping their
+/// addresses to their sizes.
+static LIVE_ALLOCATIONS: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
+thread_local! {
+    /// Set while this thread is updating `LIVE_ALLOCATIONS`, so that the
+    /// allocations made by the map itself are not recorded in it.
+    static UPDATING: Cell<bool> = const { Cell::new(false) };
+}
+
+fn live_allocations() -> MutexGuard<'static, BTreeMap<usize, usize>> {
+    LIVE_ALLOCATIONS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Apply `f` to `LIVE_ALLOCATIONS`, unless this thread is already doing so.
+fn update_live_allocations(f: impl FnOnce(&mut BTreeMap<usize, usize>)) {
+    let _ = UPDATING.try_with(|updating| {
+        if !updating.replace(true) {
+            f(&mut live_allocations());
+            updating.set(false);
+        }
+    });
+}
+
+/// A `GlobalAlloc` that forwards to another one, while recording the size of
+/// each live allocation for [`usable_size`] and [`enclosing_usable_size`].
+pub struct TrackingAllocator<A> {
+    inner: A,
+}
+
+impl<A> TrackingAllocator<A> {
+    /// Wrap `inner`. This is a `const fn`, so that the result can be used as
+    /// the `#[global_allocator]`.
+    pub const fn new(inner: A) -> Self {
+        TrackingAllocator { inner }
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for TrackingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if !ptr.is_null() {
+            update_live_allocations(|live| {
+                live.insert(ptr as usize, layout.size());
+            });
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            update_live_allocations(|live| {
+                live.insert(ptr as usize, layout.size());
+            });
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        update_live_allocations(|live| {
+            live.remove(&(ptr as usize));
+        });
+        self.inner.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = self.inner.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            update_live_allocations(|live| {
+                live.remove(&(ptr as usize));
+                live.insert(new_ptr as usize, new_size);
+            });
+        }
+        new_ptr
+    }
+}
+
+/// Get the size of the live allocation starting at `ptr`, or 0 if there is no
+/// such allocation.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn usable_size(ptr: *const c_void) -> usize {
+    live_allocations()
+        .get(&(ptr as usize))
+        .copied()
+        .unwrap_or(0)
+}
+
+/// Like [`usable_size`], but for `len` pointers at once, looking them all up
+/// while holding the lock once.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn batch_usable_size(
+    ptrs: *const *const c_void,
+    sizes: *mut usize,
+    len: usize,
+) {
+    let live = live_allocations();
+    for i in 0..len {
+        let size = live.get(&(*ptrs.add(i) as usize)).copied().unwrap_or(0);
+        *sizes.add(i) = size;
+    }
+}
+
+/// Get the size of the live allocation containing `ptr`, which may be an
+/// interior pointer, or 0 if there is no such allocation.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn enclosing_usable_size(ptr: *const c_void) -> usize {
+    let addr = ptr as usize;
+    live_allocations()
+        .range(..=addr)
+        .next_back()
+        .filter(|&(&start, &size)| addr < start + size)
+        .map_or(0, |(_, &size)| size)
+}
+
+/// A builder for `MallocSizeOfOps` that uses [`usable_size`],
+/// [`enclosing_usable_size`] and [`batch_usable_size`]. This only gives
+/// meaningful results if a `TrackingAllocator` is the global allocator.
+pub fn builder() -> MallocSizeOfOpsBuilder {
+    MallocSizeOfOps::builder()
+        .size_of_op(usable_size)
+        .enclosing_size_of_op(enclosing_usable_size)
+        .batch_size_of_op(batch_usable_size)
+}
@@ -0,0 +1,54 @@
+use libmimalloc_sys as ffi;
+use malloc_size_of::{mimalloc, MallocShallowSizeOf, MallocSizeOf};
+use std::alloc::{GlobalAlloc, Layout};
+use std::collections::VecDeque;
+use std::ffi::c_void;
+use std::mem::size_of;
+
+/// mimalloc as the global allocator, so that `mimalloc::usable_size` can be
+/// used on everything the tests allocate.
+struct Mimalloc;
+
+unsafe impl GlobalAlloc for Mimalloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ffi::mi_malloc_aligned(layout.size(), layout.align()).cast()
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        ffi::mi_zalloc_aligned(layout.size(), layout.align()).cast()
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _: Layout) {
+        ffi::mi_free(ptr.cast::<c_void>());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ffi::mi_realloc_aligned(ptr.cast::<c_void>(), new_size, layout.align()).cast()
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Mimalloc = Mimalloc;
+
+#[test]
+fn test_usable_sizes() {
+    let mut ops = mimalloc::builder().build();
+
+    let v: Vec<u8> = Vec::with_capacity(100);
+    let n = v.size_of(&mut ops);
+    assert!((100..100 + 32).contains(&n), "{}", n);
+    assert_eq!(unsafe { ops.malloc_size_of(Vec::<u64>::new().as_ptr()) }, 0);
+
+    let nested = vec![String::from("abc"), String::from("defgh")];
+    let n = nested.size_of(&mut ops);
+    assert!(n >= 2 * size_of::<String>() + 3 + 5, "{}", n);
+
+    let b: Box<[u64]> = vec![0; 1 << 17].into_boxed_slice();
+    assert!(b.size_of(&mut ops) >= 1 << 20);
+
+    // Without an `enclosing_size_of_op`, interior pointers are estimated.
+    let d: VecDeque<u32> = VecDeque::with_capacity(16);
+    assert_eq!(d.shallow_size_of(&mut ops), d.capacity() * 4);
+    assert_eq!(ops.estimated_bytes(), d.capacity() * 4);
+    assert!(ops.measured_bytes() >= 100 + 2 * size_of::<String>() + 8 + (1 << 20));
+}
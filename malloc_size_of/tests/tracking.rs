@@ -0,0 +1,106 @@
+use malloc_size_of::tracking::{self, TrackingAllocator};
+use malloc_size_of::{MallocShallowSizeOf, MallocSizeOf, MallocSizeOfOps};
+use std::alloc::System;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::mem::size_of;
+
+#[global_allocator]
+static ALLOCATOR: TrackingAllocator<System> = TrackingAllocator::new(System);
+
+#[test]
+fn test_exact_sizes() {
+    let mut ops = tracking::builder().build();
+
+    let v: Vec<u64> = Vec::with_capacity(10);
+    assert_eq!(v.size_of(&mut ops), 80);
+
+    let s = String::from("hello");
+    assert_eq!(s.size_of(&mut ops), 5);
+
+    let b: Box<[u16]> = vec![0; 7].into_boxed_slice();
+    assert_eq!(b.size_of(&mut ops), 14);
+
+    let nested = vec![String::from("abc"), String::from("defgh")];
+    assert_eq!(nested.size_of(&mut ops), 2 * size_of::<String>() + 3 + 5);
+}
+
+#[test]
+fn test_interior_pointers() {
+    let mut ops = tracking::builder().build();
+
+    let mut d: VecDeque<u32> = VecDeque::with_capacity(16);
+    d.extend(0..10);
+    d.drain(..5);
+    assert_eq!(d.shallow_size_of(&mut ops), d.capacity() * 4);
+
+    let v: Vec<u8> = Vec::with_capacity(100);
+    let interior = unsafe { v.as_ptr().add(50) };
+    assert_eq!(
+        unsafe { tracking::enclosing_usable_size(interior.cast()) },
+        100
+    );
+
+    let m: HashMap<u32, u32> = (0..10).map(|i| (i, i)).collect();
+    assert!(m.shallow_size_of(&mut ops) > m.len() * 8);
+}
+
+#[test]
+fn test_btree_nodes() {
+    let mut ops = tracking::builder().build();
+    let mut estimate_ops = MallocSizeOfOps::builder().build();
+
+    let m: BTreeMap<u64, u64> = (0..1000).map(|i| (i, i)).collect();
+    let size = m.shallow_size_of(&mut ops);
+    assert!(size > m.len() * 16);
+    assert_eq!(m.shallow_size_of(&mut estimate_ops), size);
+
+    let s: BTreeSet<u32> = (0..1000).collect();
+    let size = s.shallow_size_of(&mut ops);
+    assert!(size > s.len() * 4);
+    assert_eq!(s.shallow_size_of(&mut estimate_ops), size);
+
+    let m: BTreeMap<(), u8> = Some(((), 1)).into_iter().collect();
+    assert!(m.shallow_size_of(&mut ops) > 0);
+    assert_eq!(BTreeMap::<u8, u8>::new().shallow_size_of(&mut ops), 0);
+}
+
+#[test]
+fn test_cleared_collections() {
+    let mut ops = tracking::builder().build();
+
+    let mut d: VecDeque<u64> = (0..100).collect();
+    d.clear();
+    assert_eq!(d.shallow_size_of(&mut ops), d.capacity() * 8);
+    assert_eq!(VecDeque::<u64>::new().shallow_size_of(&mut ops), 0);
+
+    let mut m: HashMap<u64, u64> = (0..100).map(|i| (i, i)).collect();
+    m.clear();
+    assert_eq!(ops.estimated_bytes(), 0);
+    assert!(m.shallow_size_of(&mut ops) >= m.capacity() * 16);
+    assert!(ops.estimated_bytes() > 0);
+
+    let mut s: HashSet<u32> = (0..100).collect();
+    s.clear();
+    assert!(s.shallow_size_of(&mut ops) >= s.capacity() * 4);
+    assert_eq!(HashSet::<u32>::new().shallow_size_of(&mut ops), 0);
+}
+
+#[test]
+fn test_hash_table_estimates() {
+    let mut ops = tracking::builder().build();
+    let mut estimate_ops = MallocSizeOfOps::builder().build();
+
+    for n in [1, 3, 4, 7, 8, 14, 15, 100, 1000] {
+        let m: HashMap<u64, [u8; 3]> = (0..n).map(|i| (i, [0; 3])).collect();
+        assert_eq!(
+            m.shallow_size_of(&mut estimate_ops),
+            m.shallow_size_of(&mut ops),
+        );
+
+        let s: HashSet<u16> = (0..n as u16).collect();
+        assert_eq!(
+            s.shallow_size_of(&mut estimate_ops),
+            s.shallow_size_of(&mut ops),
+        );
+    }
+}
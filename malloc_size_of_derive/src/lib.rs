@@ -8,55 +8,363 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-//! A crate for deriving the MallocSizeOf trait.
+//! A crate for deriving the MallocSizeOf trait, and the related
+//! MallocUnconditionalSizeOf, MallocConditionalSizeOf and MallocShallowSizeOf
+//! traits.
 
 use syn::parse_quote;
+use syn::punctuated::Punctuated;
 use synstructure::quote;
 
 #[cfg(not(test))]
-synstructure::decl_derive!([MallocSizeOf, attributes(ignore_malloc_size_of, conditional_malloc_size_of)] => malloc_size_of_derive);
+synstructure::decl_derive!([MallocSizeOf, attributes(ignore_malloc_size_of, conditional_malloc_size_of, malloc_size_of)] => malloc_size_of_derive);
+#[cfg(not(test))]
+synstructure::decl_derive!([MallocUnconditionalSizeOf, attributes(ignore_malloc_size_of, conditional_malloc_size_of, malloc_size_of)] => malloc_unconditional_size_of_derive);
+#[cfg(not(test))]
+synstructure::decl_derive!([MallocConditionalSizeOf, attributes(ignore_malloc_size_of, conditional_malloc_size_of, malloc_size_of)] => malloc_conditional_size_of_derive);
+#[cfg(not(test))]
+synstructure::decl_derive!([MallocShallowSizeOf, attributes(ignore_malloc_size_of, conditional_malloc_size_of, malloc_size_of)] => malloc_shallow_size_of_derive);
 
-fn malloc_size_of_derive(s: synstructure::Structure) -> proc_macro2::TokenStream {
-    let match_body = s.each(|binding| {
-        let mut ignore = false;
-        let mut conditional = false;
-        for attr in binding.ast().attrs.iter() {
-            match attr.meta {
-                syn::Meta::Path(ref path) | syn::Meta::List(syn::MetaList { ref path, .. }) => {
-                    assert!(
-                        !path.is_ident("ignore_malloc_size_of"),
+/// What the attributes on a field say about how to measure it.
+#[derive(Default)]
+struct FieldAttrs {
+    ignore: bool,
+    conditional: bool,
+    /// A function measuring the field in place of `MallocSizeOf`.
+    with: Option<syn::Path>,
+}
+
+/// Adds `error` to `errors`, so that all of them are reported together.
+fn push_error(errors: &mut Option<syn::Error>, error: syn::Error) {
+    match errors {
+        Some(errors) => errors.combine(error),
+        None => *errors = Some(error),
+    }
+}
+
+/// Parses a `#[malloc_size_of(...)]` attribute. `parse_key` handles the keys
+/// that are valid where the attribute appears, returning `false` for the rest.
+fn parse_malloc_size_of_attr(
+    attr: &syn::Attribute,
+    errors: &mut Option<syn::Error>,
+    mut parse_key: impl FnMut(&syn::meta::ParseNestedMeta) -> syn::Result<bool>,
+) {
+    let result = attr.parse_nested_meta(|meta| {
+        if parse_key(&meta)? {
+            return Ok(());
+        }
+        let key: Vec<_> = meta
+            .path
+            .segments
+            .iter()
+            .map(|s| s.ident.to_string())
+            .collect();
+        let key = key.join("::");
+        Err(meta.error(format!("unknown malloc_size_of attribute `{}`", key)))
+    });
+    if let Err(error) = result {
+        push_error(errors, error);
+    }
+}
+
+/// Parses a field's attributes, adding any misuse to `errors`.
+fn field_attrs(field: &syn::Field, errors: &mut Option<syn::Error>) -> FieldAttrs {
+    let mut attrs = FieldAttrs::default();
+    for attr in field.attrs.iter() {
+        let path = attr.path();
+        if path.is_ident("ignore_malloc_size_of") {
+            if let syn::Meta::NameValue(..) = attr.meta {
+                attrs.ignore = true;
+            } else {
+                push_error(
+                    errors,
+                    syn::Error::new_spanned(
+                        attr,
                         "#[ignore_malloc_size_of] should have an explanation, \
-                         e.g. #[ignore_malloc_size_of = \"because reasons\"]"
-                    );
-                    if path.is_ident("conditional_malloc_size_of") {
-                        conditional = true;
-                    }
+                         e.g. #[ignore_malloc_size_of = \"because reasons\"]",
+                    ),
+                );
+            }
+        } else if path.is_ident("conditional_malloc_size_of") {
+            attrs.conditional = true;
+        } else if path.is_ident("malloc_size_of") {
+            parse_malloc_size_of_attr(attr, errors, |meta| {
+                if !meta.path.is_ident("with") {
+                    return Ok(false);
                 }
-                syn::Meta::NameValue(syn::MetaNameValue { ref path, .. }) => {
-                    if path.is_ident("ignore_malloc_size_of") {
-                        ignore = true;
-                    }
-                    if path.is_ident("conditional_malloc_size_of") {
-                        conditional = true;
-                    }
+                if attrs.with.is_some() {
+                    return Err(meta.error("duplicate malloc_size_of attribute `with`"));
                 }
-            }
+                let path: syn::LitStr = meta.value()?.parse()?;
+                attrs.with = Some(path.parse()?);
+                Ok(true)
+            });
         }
+    }
 
-        assert!(
-            !ignore || !conditional,
-            "ignore_malloc_size_of and conditional_malloc_size_of are incompatible"
+    if attrs.ignore && attrs.conditional {
+        push_error(
+            errors,
+            syn::Error::new_spanned(
+                field,
+                "ignore_malloc_size_of and conditional_malloc_size_of are incompatible",
+            ),
+        );
+    }
+    if attrs.with.is_some() && (attrs.ignore || attrs.conditional) {
+        push_error(
+            errors,
+            syn::Error::new_spanned(
+                field,
+                "malloc_size_of(with) is incompatible with ignore_malloc_size_of \
+                 and conditional_malloc_size_of",
+            ),
         );
+    }
+    attrs
+}
 
-        if ignore {
-            return None;
+/// Whether the struct's last field, the only one allowed to be unsized, might
+/// be: a slice, `str`, a trait object, or a type mentioning a `?Sized` type
+/// parameter. `never_owns_heap` requires `Sized`, so it can't be asked of such
+/// a field.
+fn may_be_unsized(s: &synstructure::Structure) -> bool {
+    let ast = s.ast();
+    let last = match ast.data {
+        syn::Data::Struct(ref data) => match data.fields.iter().last() {
+            Some(field) => &field.ty,
+            None => return false,
+        },
+        _ => return false,
+    };
+    match last {
+        syn::Type::Slice(..) | syn::Type::TraitObject(..) => return true,
+        syn::Type::Path(ty) if ty.qself.is_none() && ty.path.is_ident("str") => return true,
+        _ => {}
+    }
+
+    let is_maybe = |bounds: &Punctuated<syn::TypeParamBound, syn::Token![+]>| {
+        bounds.iter().any(|bound| {
+            matches!(bound, syn::TypeParamBound::Trait(bound)
+                if matches!(bound.modifier, syn::TraitBoundModifier::Maybe(..)))
+        })
+    };
+    let mut maybe_unsized: Vec<_> = ast
+        .generics
+        .type_params()
+        .filter(|param| is_maybe(&param.bounds))
+        .map(|param| param.ident.to_string())
+        .collect();
+    if let Some(ref where_clause) = ast.generics.where_clause {
+        for predicate in where_clause.predicates.iter() {
+            if let syn::WherePredicate::Type(predicate) = predicate {
+                if is_maybe(&predicate.bounds) {
+                    let ty = &predicate.bounded_ty;
+                    maybe_unsized.push(quote!(#ty).to_string());
+                }
+            }
+        }
+    }
+
+    mentions(quote!(#last), &maybe_unsized)
+}
+
+/// Whether `tokens` contain any of `idents`, looking inside groups.
+fn mentions(tokens: proc_macro2::TokenStream, idents: &[String]) -> bool {
+    tokens.into_iter().any(|token| match token {
+        proc_macro2::TokenTree::Ident(ident) => idents.contains(&ident.to_string()),
+        proc_macro2::TokenTree::Group(group) => mentions(group.stream(), idents),
+        _ => false,
+    })
+}
+
+/// Parses the container's `#[malloc_size_of(...)]` attributes, returning the
+/// predicates of a `bound` key if there is one.
+fn container_bound(
+    s: &synstructure::Structure,
+    errors: &mut Option<syn::Error>,
+) -> Option<Punctuated<syn::WherePredicate, syn::Token![,]>> {
+    let mut bound = None;
+    for attr in s.ast().attrs.iter() {
+        if attr.path().is_ident("malloc_size_of") {
+            parse_malloc_size_of_attr(attr, errors, |meta| {
+                if !meta.path.is_ident("bound") {
+                    return Ok(false);
+                }
+                if bound.is_some() {
+                    return Err(meta.error("duplicate malloc_size_of attribute `bound`"));
+                }
+                let predicates: syn::LitStr = meta.value()?.parse()?;
+                bound = Some(predicates.parse_with(Punctuated::parse_terminated)?);
+                Ok(true)
+            });
+        }
+    }
+    bound
+}
+
+/// Builds the where-clause of a derived impl: the type's own where-clause,
+/// plus either the `bound` override or `inferred`.
+fn impl_where_clause(
+    s: &synstructure::Structure,
+    bound: Option<Punctuated<syn::WherePredicate, syn::Token![,]>>,
+    inferred: impl IntoIterator<Item = syn::WherePredicate>,
+) -> syn::WhereClause {
+    let generics = &s.ast().generics;
+    let mut where_clause = generics
+        .where_clause
+        .clone()
+        .unwrap_or_else(|| parse_quote!(where));
+    match bound {
+        Some(bound) => where_clause.predicates.extend(bound),
+        None => where_clause.predicates.extend(inferred),
+    }
+    where_clause
+}
+
+/// The trait that a derive implements on top of the field walk.
+#[derive(Clone, Copy, PartialEq)]
+enum Measure {
+    SizeOf,
+    Unconditional,
+    Conditional,
+}
+
+fn malloc_size_of_derive(
+    s: synstructure::Structure,
+) -> Result<proc_macro2::TokenStream, syn::Error> {
+    derive_measure(s, Measure::SizeOf)
+}
+
+fn malloc_unconditional_size_of_derive(
+    s: synstructure::Structure,
+) -> Result<proc_macro2::TokenStream, syn::Error> {
+    derive_measure(s, Measure::Unconditional)
+}
+
+fn malloc_conditional_size_of_derive(
+    s: synstructure::Structure,
+) -> Result<proc_macro2::TokenStream, syn::Error> {
+    derive_measure(s, Measure::Conditional)
+}
+
+/// Implements `measure` by summing the fields. Fields marked
+/// `#[conditional_malloc_size_of]` are measured with `measure`'s own trait, or
+/// with `MallocConditionalSizeOf` when deriving `MallocSizeOf`, and all other
+/// fields with `MallocSizeOf`.
+fn derive_measure(
+    s: synstructure::Structure,
+    measure: Measure,
+) -> Result<proc_macro2::TokenStream, syn::Error> {
+    let mut errors = None;
+    let bound = container_bound(&s, &mut errors);
+    let bindings = s.variants().iter().flat_map(|v| v.bindings());
+    let all_attrs: Vec<_> = bindings
+        .clone()
+        .map(|binding| field_attrs(binding.ast(), &mut errors))
+        .collect();
+    if let Some(errors) = errors {
+        return Err(errors);
+    }
+
+    let mut never_owns_heap = quote! { true };
+    for (binding, attrs) in bindings.zip(&all_attrs) {
+        if attrs.conditional || attrs.with.is_some() {
+            never_owns_heap = quote! { false };
+            break;
+        }
+        if !attrs.ignore {
+            let ty = &binding.ast().ty;
+            never_owns_heap.extend(quote! {
+                && <#ty as ::malloc_size_of::MallocSizeOf>::never_owns_heap()
+            });
         }
+    }
+
+    let (trait_path, method) = match measure {
+        Measure::SizeOf => (
+            quote! { ::malloc_size_of::MallocSizeOf },
+            quote! { size_of },
+        ),
+        Measure::Unconditional => (
+            quote! { ::malloc_size_of::MallocUnconditionalSizeOf },
+            quote! { unconditional_size_of },
+        ),
+        Measure::Conditional => (
+            quote! { ::malloc_size_of::MallocConditionalSizeOf },
+            quote! { conditional_size_of },
+        ),
+    };
+    // The trait and method measuring a field, given its attributes.
+    let field_trait = |attrs: &FieldAttrs| {
+        if attrs.conditional && measure != Measure::SizeOf {
+            (trait_path.clone(), method.clone())
+        } else if attrs.conditional {
+            (
+                quote! { ::malloc_size_of::MallocConditionalSizeOf },
+                quote! { conditional_size_of },
+            )
+        } else {
+            (
+                quote! { ::malloc_size_of::MallocSizeOf },
+                quote! { size_of },
+            )
+        }
+    };
 
-        let path = if conditional {
-            quote! { ::malloc_size_of::MallocConditionalSizeOf::conditional_size_of }
+    // Unless overridden, each measured field whose type mentions a type
+    // parameter is bounded by the trait measuring it, or its element type if
+    // it is an array. A bound on a field mentioning the type itself, like
+    // `Vec<Self>`, can't be proven, so its type parameters are bounded by
+    // `MallocSizeOf` instead.
+    let own_names = [s.ast().ident.to_string(), "Self".to_string()];
+    let mut inferred: Vec<syn::WherePredicate> = Vec::new();
+    for (binding, attrs) in s
+        .variants()
+        .iter()
+        .flat_map(|v| v.bindings())
+        .zip(&all_attrs)
+    {
+        if attrs.ignore || attrs.with.is_some() || binding.referenced_ty_params().is_empty() {
+            continue;
+        }
+        let ty = match binding.ast().ty {
+            syn::Type::Array(ref array) => &*array.elem,
+            ref ty => ty,
+        };
+        let predicates: Vec<syn::WherePredicate> = if mentions(quote!(#ty), &own_names) {
+            binding
+                .referenced_ty_params()
+                .into_iter()
+                .map(|param| parse_quote!(#param: ::malloc_size_of::MallocSizeOf))
+                .collect()
         } else {
-            quote! { ::malloc_size_of::MallocSizeOf::size_of }
+            let (field_trait, _) = field_trait(attrs);
+            vec![parse_quote!(#ty: #field_trait)]
         };
+        for predicate in predicates {
+            if !inferred.contains(&predicate) {
+                inferred.push(predicate);
+            }
+        }
+    }
+
+    // `each` visits the bindings in the same order as `all_attrs`.
+    let mut all_attrs = all_attrs.iter();
+    let match_body = s.each(|binding| {
+        let attrs = all_attrs.next().unwrap();
+
+        if attrs.ignore {
+            return None;
+        }
+        if let Some(with) = &attrs.with {
+            return Some(quote! {
+                sum += #with(#binding, ops);
+            });
+        }
+
+        let (field_trait, field_method) = field_trait(attrs);
+        let path = quote! { #field_trait::#field_method };
 
         if let syn::Type::Array(..) = binding.ast().ty {
             Some(quote! {
@@ -73,30 +381,100 @@ fn malloc_size_of_derive(s: synstructure::Structure) -> proc_macro2::TokenStream
 
     let ast = s.ast();
     let name = &ast.ident;
-    let (impl_generics, ty_generics, where_clause) = ast.generics.split_for_impl();
-    let mut where_clause = where_clause.unwrap_or(&parse_quote!(where)).clone();
-    for param in ast.generics.type_params() {
-        let ident = &param.ident;
-        where_clause
-            .predicates
-            .push(parse_quote!(#ident: ::malloc_size_of::MallocSizeOf));
-    }
+    let (impl_generics, ty_generics, _) = ast.generics.split_for_impl();
+    let where_clause = impl_where_clause(&s, bound, inferred);
+
+    let never_owns_heap = if measure == Measure::SizeOf && !may_be_unsized(&s) {
+        quote! {
+            #[inline]
+            fn never_owns_heap() -> bool {
+                #never_owns_heap
+            }
+        }
+    } else {
+        quote! {}
+    };
 
     let tokens = quote! {
-        impl #impl_generics ::malloc_size_of::MallocSizeOf for #name #ty_generics #where_clause {
+        impl #impl_generics #trait_path for #name #ty_generics #where_clause {
             #[inline]
             #[allow(unused_variables, unused_mut, unreachable_code)]
-            fn size_of(&self, ops: &mut ::malloc_size_of::MallocSizeOfOps) -> usize {
+            fn #method(&self, ops: &mut ::malloc_size_of::MallocSizeOfOps) -> usize {
                 let mut sum = 0;
                 match *self {
                     #match_body
                 }
                 sum
             }
+
+            #never_owns_heap
         }
     };
 
-    tokens
+    Ok(tokens)
+}
+
+/// Implements `MallocShallowSizeOf` for a newtype by forwarding to its field.
+fn malloc_shallow_size_of_derive(
+    s: synstructure::Structure,
+) -> Result<proc_macro2::TokenStream, syn::Error> {
+    let mut errors = None;
+    let bound = container_bound(&s, &mut errors);
+    let variants = s.variants();
+    if !matches!(s.ast().data, syn::Data::Struct(..)) || variants[0].bindings().len() != 1 {
+        push_error(
+            &mut errors,
+            syn::Error::new_spanned(
+                &s.ast().ident,
+                "MallocShallowSizeOf can only be derived for structs with exactly one field",
+            ),
+        );
+    } else {
+        let field = variants[0].bindings()[0].ast();
+        let attrs = field_attrs(field, &mut errors);
+        if attrs.ignore || attrs.conditional || attrs.with.is_some() {
+            push_error(
+                &mut errors,
+                syn::Error::new_spanned(
+                    field,
+                    "MallocShallowSizeOf is derived by forwarding to the field, \
+                     which can't be ignored, conditional, or measured with a function",
+                ),
+            );
+        }
+    }
+    if let Some(errors) = errors {
+        return Err(errors);
+    }
+
+    let binding = &variants[0].bindings()[0];
+    let ty = &binding.ast().ty;
+    let inferred: Option<syn::WherePredicate> = if binding.referenced_ty_params().is_empty() {
+        None
+    } else {
+        Some(parse_quote!(#ty: ::malloc_size_of::MallocShallowSizeOf))
+    };
+    let where_clause = impl_where_clause(&s, bound, inferred);
+
+    let match_body = s.each(|binding| {
+        quote! {
+            ::malloc_size_of::MallocShallowSizeOf::shallow_size_of(#binding, ops)
+        }
+    });
+
+    let ast = s.ast();
+    let name = &ast.ident;
+    let (impl_generics, ty_generics, _) = ast.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::malloc_size_of::MallocShallowSizeOf for #name #ty_generics #where_clause {
+            #[inline]
+            fn shallow_size_of(&self, ops: &mut ::malloc_size_of::MallocSizeOfOps) -> usize {
+                match *self {
+                    #match_body
+                }
+            }
+        }
+    })
 }
 
 #[test]
@@ -107,7 +485,7 @@ fn test_struct() {
     .unwrap();
     let source = synstructure::Structure::new(&source);
 
-    let expanded = malloc_size_of_derive(source).to_string();
+    let expanded = malloc_size_of_derive(source).unwrap().to_string();
     let mut no_space = expanded.replace(" ", "");
     macro_rules! match_count {
         ($e: expr, $count: expr) => {
@@ -124,17 +502,204 @@ fn test_struct() {
     match_count!("ignore_malloc_size_of", 0);
     match_count!("impl<T> ::malloc_size_of::MallocSizeOf for Foo<T> where T: ::malloc_size_of::MallocSizeOf {", 1);
     match_count!("sum += ::malloc_size_of::MallocSizeOf::size_of(", 2);
+    match_count!("true && <Bar as ::malloc_size_of::MallocSizeOf>::never_owns_heap() && <T as ::malloc_size_of::MallocSizeOf>::never_owns_heap() }", 1);
 
     let source = syn::parse_str("struct Bar([Baz; 3]);").unwrap();
     let source = synstructure::Structure::new(&source);
-    let expanded = malloc_size_of_derive(source).to_string();
+    let expanded = malloc_size_of_derive(source).unwrap().to_string();
     no_space = expanded.replace(" ", "");
     match_count!("for item in", 1);
+
+    let source =
+        syn::parse_str("enum E { A(u8), B(#[conditional_malloc_size_of] Arc<C>) }").unwrap();
+    let source = synstructure::Structure::new(&source);
+    let expanded = malloc_size_of_derive(source).unwrap().to_string();
+    no_space = expanded.replace(" ", "");
+    match_count!("fn never_owns_heap() -> bool { false }", 1);
+
+    let source = syn::parse_str(
+        "struct F { #[malloc_size_of(with = \"ffi::handle_size_of\")] h: [Handle; 2] }",
+    )
+    .unwrap();
+    let source = synstructure::Structure::new(&source);
+    let expanded = malloc_size_of_derive(source).unwrap().to_string();
+    no_space = expanded.replace(" ", "");
+    match_count!("sum += ffi::handle_size_of(", 1);
+    match_count!("for item in", 0);
+    match_count!("fn never_owns_heap() -> bool { false }", 1);
+}
+
+#[test]
+fn test_unsized() {
+    for source in [
+        "struct Dst { len: usize, data: [u8] }",
+        "struct Text { s: str }",
+        "struct Obj { o: dyn Trait }",
+        "struct Wrapper<T: ?Sized> { a: u8, inner: Inner<T> }",
+        "struct Wrapper<T> where T: ?Sized { inner: T }",
+    ] {
+        let source = syn::parse_str(source).unwrap();
+        let source = synstructure::Structure::new(&source);
+        let expanded = malloc_size_of_derive(source).unwrap().to_string();
+        assert!(!expanded.contains("never_owns_heap"), "{}", expanded);
+    }
+
+    let source = syn::parse_str("struct Boxed<T: ?Sized> { inner: Box<T>, len: usize }").unwrap();
+    let source = synstructure::Structure::new(&source);
+    let expanded = malloc_size_of_derive(source).unwrap().to_string();
+    assert!(expanded.contains("never_owns_heap"), "{}", expanded);
+}
+
+#[test]
+fn test_bounds() {
+    let source = syn::parse_str(
+        "struct Id<T, U, V, W> { \
+            raw: u32, \
+            _t: PhantomData<T>, \
+            #[ignore_malloc_size_of = \"\"] u: Box<U>, \
+            #[conditional_malloc_size_of] v: Arc<V>, \
+            #[malloc_size_of(with = \"f\")] w: W, \
+         }",
+    )
+    .unwrap();
+    let source = synstructure::Structure::new(&source);
+    let expanded = malloc_size_of_derive(source).unwrap().to_string();
+    let no_space = expanded.replace(" ", "");
+    assert!(
+        no_space.contains(
+            "forId<T,U,V,W>wherePhantomData<T>:::malloc_size_of::MallocSizeOf,\
+             Arc<V>:::malloc_size_of::MallocConditionalSizeOf{"
+        ),
+        "{}",
+        expanded
+    );
+
+    // An id type that is measurable whatever its parameter doesn't bound it.
+    let source =
+        syn::parse_str("struct Handle<T> { id: Id<T>, ids: [Id<T>; 4], gen: u32 }").unwrap();
+    let source = synstructure::Structure::new(&source);
+    let expanded = malloc_size_of_derive(source).unwrap().to_string();
+    let no_space = expanded.replace(" ", "");
+    assert!(
+        no_space.contains("forHandle<T>whereId<T>:::malloc_size_of::MallocSizeOf{"),
+        "{}",
+        expanded
+    );
+
+    // Recursive fields bound the parameters instead, because the field type's
+    // own bound would depend on the impl being derived.
+    for source in [
+        "struct List<T> { value: T, next: Option<Box<List<T>>> }",
+        "struct Tree<T> { value: T, children: Vec<Tree<T>> }",
+    ] {
+        let source = syn::parse_str(source).unwrap();
+        let source = synstructure::Structure::new(&source);
+        let expanded = malloc_size_of_derive(source).unwrap().to_string();
+        let no_space = expanded.replace(" ", "");
+        assert!(
+            no_space.contains("<T>whereT:::malloc_size_of::MallocSizeOf{"),
+            "{}",
+            expanded
+        );
+    }
+
+    let source =
+        syn::parse_str("#[malloc_size_of(bound = \"T: Sized\")] struct Handle<T: Clone> { t: T }")
+            .unwrap();
+    let source = synstructure::Structure::new(&source);
+    let expanded = malloc_size_of_derive(source).unwrap().to_string();
+    let no_space = expanded.replace(" ", "");
+    assert!(
+        no_space.contains("forHandle<T>whereT:Sized{"),
+        "{}",
+        expanded
+    );
+}
+
+#[test]
+fn test_other_traits() {
+    let input = syn::parse_str(
+        "struct Node { #[conditional_malloc_size_of] inner: Arc<Inner>, name: String }",
+    )
+    .unwrap();
+    let expanded = malloc_unconditional_size_of_derive(synstructure::Structure::new(&input))
+        .unwrap()
+        .to_string()
+        .replace(" ", "");
+    assert!(expanded.contains("::malloc_size_of::MallocUnconditionalSizeOfforNode{"));
+    assert!(expanded.contains(
+        "sum+=::malloc_size_of::MallocUnconditionalSizeOf::unconditional_size_of(__binding_0,ops);"
+    ));
+    assert!(expanded.contains("sum+=::malloc_size_of::MallocSizeOf::size_of(__binding_1,ops);"));
+    assert!(!expanded.contains("never_owns_heap"));
+
+    let expanded = malloc_conditional_size_of_derive(synstructure::Structure::new(&input))
+        .unwrap()
+        .to_string()
+        .replace(" ", "");
+    assert!(expanded.contains("::malloc_size_of::MallocConditionalSizeOfforNode{"));
+    assert!(expanded.contains(
+        "sum+=::malloc_size_of::MallocConditionalSizeOf::conditional_size_of(__binding_0,ops);"
+    ));
+    assert!(expanded.contains("sum+=::malloc_size_of::MallocSizeOf::size_of(__binding_1,ops);"));
+
+    let input = syn::parse_str(
+        "struct Node<T> { \
+            #[conditional_malloc_size_of] inner: Arc<T>, \
+            #[malloc_size_of(with = \"f\")] extra: Vec<u8>, \
+         }",
+    )
+    .unwrap();
+    let expanded = malloc_unconditional_size_of_derive(synstructure::Structure::new(&input))
+        .unwrap()
+        .to_string()
+        .replace(" ", "");
+    assert!(expanded.contains("forNode<T>whereArc<T>:::malloc_size_of::MallocUnconditionalSizeOf{"));
+    assert!(expanded.contains("sum+=f(__binding_1,ops);"));
+
+    let input = syn::parse_str("struct Wrapper<T>(Vec<T>);").unwrap();
+    let expanded = malloc_shallow_size_of_derive(synstructure::Structure::new(&input))
+        .unwrap()
+        .to_string()
+        .replace(" ", "");
+    assert!(expanded.contains(
+        "::malloc_size_of::MallocShallowSizeOfforWrapper<T>whereVec<T>:::malloc_size_of::MallocShallowSizeOf{"
+    ));
+
+    let input = syn::parse_str("struct Pair(Vec<u8>, Vec<u8>);").unwrap();
+    let error = malloc_shallow_size_of_derive(synstructure::Structure::new(&input)).unwrap_err();
+    assert!(error.to_string().contains("exactly one field"));
 }
 
-#[should_panic(expected = "should have an explanation")]
 #[test]
 fn test_no_reason() {
     let input = syn::parse_str("struct A { #[ignore_malloc_size_of] b: C }").unwrap();
-    malloc_size_of_derive(synstructure::Structure::new(&input));
+    let error = malloc_size_of_derive(synstructure::Structure::new(&input)).unwrap_err();
+    assert!(error.to_string().contains("should have an explanation"));
+}
+
+#[test]
+fn test_errors() {
+    let input = syn::parse_str(
+        "#[malloc_size_of(foo)] struct A { \
+            #[ignore_malloc_size_of = \"\"] #[conditional_malloc_size_of] b: B, \
+            #[malloc_size_of(bar = \"\")] c: C, \
+            #[malloc_size_of(with = \"f\")] #[conditional_malloc_size_of] d: D, \
+            #[malloc_size_of(with = \"f\", with = \"g\")] e: E, \
+         }",
+    )
+    .unwrap();
+    let error = malloc_size_of_derive(synstructure::Structure::new(&input)).unwrap_err();
+    let messages: Vec<_> = error.into_iter().map(|e| e.to_string()).collect();
+    assert_eq!(
+        messages,
+        [
+            "unknown malloc_size_of attribute `foo`",
+            "ignore_malloc_size_of and conditional_malloc_size_of are incompatible",
+            "unknown malloc_size_of attribute `bar`",
+            "malloc_size_of(with) is incompatible with ignore_malloc_size_of \
+             and conditional_malloc_size_of",
+            "duplicate malloc_size_of attribute `with`",
+        ]
+    );
 }